@@ -1,6 +1,7 @@
 use std::io::Result;
 
 fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=migrations");
     // prost_build::compile_protos(&["proto/election.proto"], &["proto/"])?;
     Ok(())
 }
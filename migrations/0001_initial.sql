@@ -0,0 +1,58 @@
+-- Schema of the databases created before migrations were introduced.
+-- The tables may already exist, in which case this migration only
+-- records the baseline version.
+CREATE TABLE IF NOT EXISTS properties(
+    id_property INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    value TEXT NOT NULL);
+
+CREATE TABLE IF NOT EXISTS ballots(
+    id_ballot INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL UNIQUE,
+    data BLOB NOT NULL);
+
+CREATE TABLE IF NOT EXISTS nfs(
+    id_nf INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    hash BLOB NOT NULL UNIQUE);
+
+CREATE TABLE IF NOT EXISTS dnfs(
+    id_dnf INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    hash BLOB NOT NULL UNIQUE);
+
+CREATE TABLE IF NOT EXISTS cmxs(
+    id_cmx INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    hash BLOB NOT NULL UNIQUE);
+
+CREATE TABLE IF NOT EXISTS cmx_roots(
+    id_cmx_root INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    CONSTRAINT u_cmx_roots UNIQUE (election, hash));
+
+CREATE TABLE IF NOT EXISTS cmx_frontiers(
+    id_cmx_frontier INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    frontier TEXT NOT NULL,
+    CONSTRAINT u_cmx_frontiers UNIQUE (election, height));
+
+CREATE TABLE IF NOT EXISTS notes(
+    id_note INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    scope INTEGER NOT NULL,
+    position INTEGER NOT NULL UNIQUE,
+    height INTEGER NOT NULL,
+    txid BLOB NOT NULL,
+    value INTEGER NOT NULL,
+    div BLOB NOT NULL,
+    rseed BLOB NOT NULL,
+    nf BLOB NOT NULL,
+    dnf BLOB NOT NULL,
+    rho BLOB NOT NULL,
+    spent INTEGER);
@@ -0,0 +1,13 @@
+-- Heights of the nullifiers and commitments, for reorg rollbacks
+ALTER TABLE nfs ADD COLUMN height INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE cmxs ADD COLUMN height INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE blocks(
+    id_block INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    CONSTRAINT u_blocks UNIQUE (election, height));
+
+-- Notes of several accounts
+ALTER TABLE notes ADD COLUMN account INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,102 @@
+-- Elections tracked by the database, with the height they are synced to
+CREATE TABLE elections(
+    id_election INTEGER PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    data TEXT NOT NULL,
+    height INTEGER);
+
+-- Notes used to be stored under election 0. When a single election
+-- was synced, they belong to it
+WITH synced(e) AS (SELECT election FROM ballots
+    UNION SELECT election FROM nfs
+    UNION SELECT election FROM dnfs
+    UNION SELECT election FROM cmxs
+    UNION SELECT election FROM cmx_roots
+    UNION SELECT election FROM cmx_frontiers
+    UNION SELECT election FROM blocks)
+UPDATE notes SET election = (SELECT e FROM synced WHERE e <> 0)
+    WHERE election = 0 AND (SELECT COUNT(*) FROM synced WHERE e <> 0) = 1;
+
+-- Data synced before this version is kept under its election number,
+-- with an empty definition until it is claimed by
+-- `db::adopt_legacy_election`. The sync height was global
+INSERT INTO elections(id_election, id, data, height)
+    SELECT e, 'legacy-' || e, '',
+    (SELECT CAST(value AS INTEGER) FROM properties WHERE name = 'height')
+    FROM (SELECT election AS e FROM ballots
+        UNION SELECT election FROM nfs
+        UNION SELECT election FROM dnfs
+        UNION SELECT election FROM cmxs
+        UNION SELECT election FROM cmx_roots
+        UNION SELECT election FROM cmx_frontiers
+        UNION SELECT election FROM notes
+        UNION SELECT election FROM blocks);
+DELETE FROM properties WHERE name = 'height';
+
+-- Uniqueness is per election, so that elections can overlap
+CREATE TABLE new_ballots(
+    id_ballot INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    data BLOB NOT NULL,
+    CONSTRAINT u_ballots UNIQUE (election, hash));
+INSERT INTO new_ballots(id_ballot, election, height, hash, data)
+    SELECT id_ballot, election, height, hash, data FROM ballots;
+DROP TABLE ballots;
+ALTER TABLE new_ballots RENAME TO ballots;
+
+CREATE TABLE new_nfs(
+    id_nf INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    CONSTRAINT u_nfs UNIQUE (election, hash));
+INSERT INTO new_nfs(id_nf, election, height, hash)
+    SELECT id_nf, election, height, hash FROM nfs;
+DROP TABLE nfs;
+ALTER TABLE new_nfs RENAME TO nfs;
+
+CREATE TABLE new_dnfs(
+    id_dnf INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    CONSTRAINT u_dnfs UNIQUE (election, hash));
+INSERT INTO new_dnfs(id_dnf, election, hash)
+    SELECT id_dnf, election, hash FROM dnfs;
+DROP TABLE dnfs;
+ALTER TABLE new_dnfs RENAME TO dnfs;
+
+CREATE TABLE new_cmxs(
+    id_cmx INTEGER PRIMARY KEY NOT NULL,
+    election INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    hash BLOB NOT NULL,
+    CONSTRAINT u_cmxs UNIQUE (election, hash));
+INSERT INTO new_cmxs(id_cmx, election, height, hash)
+    SELECT id_cmx, election, height, hash FROM cmxs;
+DROP TABLE cmxs;
+ALTER TABLE new_cmxs RENAME TO cmxs;
+
+CREATE TABLE new_notes(
+    id_note INTEGER PRIMARY KEY,
+    election INTEGER NOT NULL,
+    account INTEGER NOT NULL,
+    scope INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    txid BLOB NOT NULL,
+    value INTEGER NOT NULL,
+    div BLOB NOT NULL,
+    rseed BLOB NOT NULL,
+    nf BLOB NOT NULL,
+    dnf BLOB NOT NULL,
+    rho BLOB NOT NULL,
+    spent INTEGER,
+    CONSTRAINT u_notes UNIQUE (election, account, position));
+INSERT INTO new_notes(id_note, election, account, scope, position, height,
+    txid, value, div, rseed, nf, dnf, rho, spent)
+    SELECT id_note, election, account, scope, position, height,
+    txid, value, div, rseed, nf, dnf, rho, spent FROM notes;
+DROP TABLE notes;
+ALTER TABLE new_notes RENAME TO notes;
@@ -0,0 +1,3 @@
+-- Where the data used to finalize the election came from
+ALTER TABLE elections ADD COLUMN server TEXT;
+ALTER TABLE elections ADD COLUMN archive_hash BLOB;
@@ -0,0 +1,230 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use blake2b_simd::Params;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use futures::StreamExt as _;
+use orchard::keys::FullViewingKey;
+use prost::Message as _;
+use sqlx::SqliteConnection;
+
+use crate::{
+    as_byte256,
+    download::{download_reference_data, SyncEvent, SyncOptions},
+    election::Election,
+    rpc::CompactBlock,
+    source::{find_block, stream_blocks, BlockReader, BlockSource, BlockStream, SyncOrigin},
+    Hash, Result,
+};
+
+const MAGIC: &[u8; 4] = b"ZVBA";
+const VERSION: u8 = 2;
+const HASH_PERSONALIZATION: &[u8; 16] = b"ZcashVoteArchive";
+
+/// Header of a block archive
+///
+/// The archive is the header followed by the deflate compressed
+/// sequence of length delimited `CompactBlock`s of the election range.
+/// `hash` is the BLAKE2b-256 hash of the election id, the heights
+/// and the uncompressed blocks
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ArchiveHeader {
+    pub election_id: String,
+    pub start_height: u32,
+    pub end_height: u32,
+    pub hash: Hash,
+}
+
+impl ArchiveHeader {
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        self.write_fields(writer)?;
+        writer.write_all(&self.hash)?;
+        Ok(())
+    }
+
+    // The fields covered by the hash
+    fn write_fields(&self, writer: &mut impl Write) -> Result<()> {
+        let election_id = hex::decode(&self.election_id).map_err(anyhow::Error::from)?;
+        writer.write_all(&as_byte256(&election_id))?;
+        writer.write_all(&self.start_height.to_le_bytes())?;
+        writer.write_all(&self.end_height.to_le_bytes())?;
+        Ok(())
+    }
+
+    // Hasher of the content, starting with the header fields so that
+    // they cannot be changed without invalidating the hash
+    fn hasher(&self) -> Result<blake2b_simd::State> {
+        let mut hasher = Params::new()
+            .hash_length(32)
+            .personal(HASH_PERSONALIZATION)
+            .to_state();
+        self.write_fields(&mut hasher)?;
+        Ok(hasher)
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if &magic != MAGIC || version[0] != VERSION {
+            return Err(anyhow!("Not a block archive").into());
+        }
+        let mut election_id = [0u8; 32];
+        reader.read_exact(&mut election_id)?;
+        let mut height = [0u8; 4];
+        reader.read_exact(&mut height)?;
+        let start_height = u32::from_le_bytes(height);
+        reader.read_exact(&mut height)?;
+        let end_height = u32::from_le_bytes(height);
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        Ok(ArchiveHeader {
+            election_id: hex::encode(election_id),
+            start_height,
+            end_height,
+            hash,
+        })
+    }
+}
+
+/// Write the blocks of the election range, taken from `source`,
+/// to an archive at `path`
+pub async fn export_archive(
+    source: &mut impl BlockSource,
+    election: &Election,
+    path: impl AsRef<Path>,
+) -> Result<ArchiveHeader> {
+    let mut header = ArchiveHeader {
+        election_id: election.id(),
+        start_height: election.start_height,
+        end_height: election.end_height,
+        hash: [0u8; 32],
+    };
+    let mut file = File::create(path)?;
+    // placeholder until the content hash is known
+    header.write(&mut file)?;
+
+    let mut hasher = header.hasher()?;
+    let mut encoder = DeflateEncoder::new(BufWriter::new(file), Compression::default());
+    let mut blocks = source
+        .get_block_range(election.start_height + 1, election.end_height)
+        .await?;
+    while let Some(block) = blocks.next().await {
+        let data = block?.encode_length_delimited_to_vec();
+        hasher.update(&data);
+        encoder.write_all(&data)?;
+    }
+    let mut file = encoder
+        .finish()?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+
+    header.hash = as_byte256(hasher.finalize().as_bytes());
+    file.seek(SeekFrom::Start(0))?;
+    header.write(&mut file)?;
+    file.sync_all()?;
+    Ok(header)
+}
+
+/// Blocks read back from an archive made by [`export_archive`]
+pub struct ArchiveBlockSource {
+    path: PathBuf,
+    header: ArchiveHeader,
+}
+
+impl ArchiveBlockSource {
+    /// Open an archive and check its content against the hash
+    /// of its header. This reads the whole archive, call it
+    /// from a blocking thread
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (header, mut decoder) = read_archive(&path)?;
+        let mut hasher = header.hasher()?;
+        std::io::copy(&mut decoder, &mut hasher)?;
+        if hasher.finalize().as_bytes() != header.hash {
+            return Err(anyhow!("Archive content does not match its hash").into());
+        }
+        Ok(ArchiveBlockSource { path, header })
+    }
+
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
+
+    /// Check that the archive covers the range of `election`
+    pub fn check_election(&self, election: &Election) -> Result<()> {
+        let header = &self.header;
+        if header.election_id != election.id()
+            || header.start_height != election.start_height
+            || header.end_height != election.end_height
+        {
+            return Err(anyhow!("Archive is not for election {}", election.id()).into());
+        }
+        Ok(())
+    }
+
+    fn blocks(path: &Path) -> Result<impl Iterator<Item = Result<CompactBlock>>> {
+        let (_, decoder) = read_archive(path)?;
+        Ok(BlockReader::new(decoder))
+    }
+}
+
+fn read_archive(path: &Path) -> Result<(ArchiveHeader, DeflateDecoder<BufReader<File>>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = ArchiveHeader::read(&mut reader)?;
+    Ok((header, DeflateDecoder::new(reader)))
+}
+
+#[async_trait]
+impl BlockSource for ArchiveBlockSource {
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream> {
+        let path = self.path.clone();
+        Ok(stream_blocks(move || Self::blocks(&path), start, end))
+    }
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock> {
+        let path = self.path.clone();
+        find_block(move || Self::blocks(&path), height).await
+    }
+
+    fn origin(&self) -> SyncOrigin {
+        SyncOrigin {
+            server: None,
+            archive_hash: Some(self.header.hash),
+        }
+    }
+}
+
+/// Replay an archive into the database, as if the blocks
+/// were downloaded from a server
+pub async fn import_archive(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    election: &Election,
+    fvks: &[(u32, FullViewingKey)],
+    path: impl AsRef<Path>,
+    options: &SyncOptions<impl Fn(SyncEvent) + Send + 'static>,
+) -> Result<u32> {
+    let path = path.as_ref().to_path_buf();
+    let mut source = tokio::task::spawn_blocking(move || ArchiveBlockSource::open(path))
+        .await
+        .map_err(anyhow::Error::from)??;
+    source.check_election(election)?;
+    download_reference_data(
+        connection,
+        id_election,
+        election,
+        fvks,
+        &mut source,
+        options,
+    )
+    .await
+}
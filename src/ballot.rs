@@ -0,0 +1,244 @@
+use anyhow::anyhow;
+use orchard::{
+    keys::{FullViewingKey, SpendingKey},
+    vote::{Ballot, InputNote, OrchardHash},
+};
+use pasta_curves::{group::ff::PrimeField as _, Fp};
+use rand_core::OsRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection as _, SqliteConnection};
+
+use crate::{
+    address::VoteAddress,
+    db::{find_election, store_ballot, store_dnf},
+    election::{Election, BALLOT_PK, BALLOT_VK},
+    errors::{InvalidBallot, VoteError},
+    trees::{append_ballot_cmxs, list_vote_notes},
+    Result, VoteNote,
+};
+
+/// A ballot made by [`create_ballot`]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BallotResult {
+    /// JSON serialized `Ballot`
+    pub ballot: Vec<u8>,
+    /// Positions of the notes spent by the ballot
+    pub notes: Vec<u32>,
+    pub amount: u64,
+    /// Voting power returned to the voter
+    pub change: u64,
+}
+
+/// Vote `amount` for `candidate` with the notes of the account.
+/// The notes are selected largest first. The election must be
+/// finalized, with the anchors of the synced database
+pub async fn create_ballot(
+    connection: &mut SqliteConnection,
+    election: &Election,
+    account: u32,
+    sk: &SpendingKey,
+    candidate: &VoteAddress,
+    amount: u64,
+) -> Result<BallotResult> {
+    if amount == 0 {
+        return Err(VoteError::ZeroAmount);
+    }
+    let raw_address = candidate.0.to_raw_address_bytes();
+    let is_candidate = election.candidates.iter().any(|c| {
+        VoteAddress::decode(&c.address).is_ok_and(|a| a.0.to_raw_address_bytes() == raw_address)
+    });
+    if !is_candidate {
+        return Err(VoteError::UnknownCandidate(candidate.to_string()));
+    }
+    let id_election = find_election(connection, &election.id())
+        .await?
+        .ok_or(anyhow!("Unknown election {}", election.id()))?;
+
+    // a proof made with other anchors would never verify
+    let nf_root = to_anchor("nf", &election.nf)?;
+    let cmx_root = to_anchor("cmx", &election.cmx)?;
+
+    let fvk = FullViewingKey::from(sk);
+    let (mut notes, nf, cmx) = list_vote_notes(connection, id_election, account, &fvk).await?;
+    if nf.0 != election.nf.0 {
+        return Err(VoteError::InvalidAnchors(
+            "nf does not match the database".to_string(),
+        ));
+    }
+    if cmx.0 != election.cmx.0 {
+        return Err(VoteError::InvalidAnchors(
+            "cmx does not match the database".to_string(),
+        ));
+    }
+    notes.sort_by_key(|n| std::cmp::Reverse(n.note.value().inner()));
+    let mut selected = vec![];
+    let mut total = 0u64;
+    for note in notes {
+        if total >= amount {
+            break;
+        }
+        total += note.note.value().inner();
+        selected.push(note);
+    }
+    if total < amount {
+        return Err(VoteError::NotEnoughVotes {
+            available: total,
+            required: amount,
+        });
+    }
+
+    let inputs = selected.iter().map(to_input_note).collect::<Vec<_>>();
+    let ballot = orchard::vote::vote(
+        election.domain(),
+        election.signature_required,
+        Some(sk.clone()),
+        &fvk,
+        candidate.0,
+        amount,
+        &inputs,
+        nf_root,
+        cmx_root,
+        OsRng,
+        &BALLOT_PK,
+        &BALLOT_VK,
+    )?;
+    let ballot = serde_json::to_vec(&ballot).map_err(anyhow::Error::from)?;
+    Ok(BallotResult {
+        ballot,
+        notes: selected.iter().map(|n| n.idx as u32).collect(),
+        amount,
+        change: total - amount,
+    })
+}
+
+fn to_anchor(name: &str, anchor: &OrchardHash) -> Result<Fp> {
+    if anchor.0 == [0u8; 32] {
+        return Err(VoteError::InvalidAnchors(format!(
+            "{name} is missing, the election is not finalized"
+        )));
+    }
+    Fp::from_repr(anchor.0)
+        .into_option()
+        .ok_or(VoteError::InvalidAnchors(format!(
+            "{name} is not a field element"
+        )))
+}
+
+fn to_input_note(note: &VoteNote) -> InputNote {
+    InputNote {
+        note: note.note,
+        nf: note.nf,
+        nf_start: note.nf_start,
+        nf_path: note.nf_path.clone(),
+        cmx_path: note.cmx_path.clone(),
+    }
+}
+
+/// Check a ballot against the election: domain, anchors, proofs and,
+/// when the election requires them, spend signatures
+pub fn verify_ballot(election: &Election, ballot: &Ballot) -> Result<()> {
+    check_ballot(election, &election.domain().to_repr(), ballot)?;
+    Ok(())
+}
+
+/// Verify many ballots of the same election on the rayon thread pool.
+/// Returns the verdict of each ballot, in the order of `ballots`.
+///
+/// The proofs are checked one ballot at a time rather than with halo2's
+/// `BatchVerifier`: the vote fork keeps the halo2 verifying key and the
+/// public inputs of a ballot private behind `validate_ballot`, and the
+/// crate depends on halo2_proofs 0.2 while the fork uses 0.3, so the
+/// proofs cannot be added to a batch from here
+pub fn verify_ballots(
+    election: &Election,
+    ballots: &[Ballot],
+) -> Vec<std::result::Result<(), InvalidBallot>> {
+    let domain = election.domain().to_repr();
+    ballots
+        .par_iter()
+        .map(|ballot| check_ballot(election, &domain, ballot))
+        .collect()
+}
+
+fn check_ballot(
+    election: &Election,
+    domain: &[u8; 32],
+    ballot: &Ballot,
+) -> std::result::Result<(), InvalidBallot> {
+    let data = &ballot.data;
+    if data.domain != *domain {
+        return Err(InvalidBallot::Domain);
+    }
+    // the proofs are only valid for the anchors they were made with,
+    // they must be the anchors published with the election
+    if data.anchors.cmx != election.cmx.0 {
+        return Err(InvalidBallot::CmxAnchor);
+    }
+    if data.anchors.nf != election.nf.0 {
+        return Err(InvalidBallot::NfAnchor);
+    }
+    if election.signature_required && ballot.witnesses.sp_signatures.is_none() {
+        return Err(InvalidBallot::MissingSignature);
+    }
+    orchard::vote::validate_ballot(ballot.clone(), election.signature_required, &BALLOT_VK)
+        .map_err(|e| match e {
+            orchard::vote::VoteError::InvalidSignature(_) => {
+                InvalidBallot::Signature(e.to_string())
+            }
+            _ => InvalidBallot::Proof(e.to_string()),
+        })?;
+    Ok(())
+}
+
+/// Verify a ballot with [`verify_ballot`], then record it and its
+/// domain nullifiers in a single transaction and append its output
+/// commitments to the ballot tree.
+/// The ballot is rejected with [`VoteError::DoubleNullifier`] if any of
+/// its nullifiers was already used in the election, or with
+/// [`VoteError::DuplicateBallot`] if it was already accepted, and nothing
+/// is stored. Returns the ballot hash
+pub async fn accept_ballot(
+    connection: &mut SqliteConnection,
+    election: &Election,
+    height: u32,
+    ballot: &Ballot,
+) -> Result<Vec<u8>> {
+    // an unverified ballot could burn the nullifiers of another voter
+    let (e, b) = (election.clone(), ballot.clone());
+    tokio::task::spawn_blocking(move || verify_ballot(&e, &b))
+        .await
+        .map_err(anyhow::Error::from)??;
+    let id_election = find_election(connection, &election.id())
+        .await?
+        .ok_or(anyhow!("Unknown election {}", election.id()))?;
+
+    let hash = ballot.data.sighash()?;
+    let data = serde_json::to_vec(ballot).map_err(anyhow::Error::from)?;
+
+    // The unique constraint on the nullifiers makes concurrent
+    // submissions of the same note fail in the database
+    let mut db_tx = connection.begin().await?;
+    for action in ballot.data.actions.iter() {
+        if let Err(e) = store_dnf(&mut db_tx, id_election, &action.nf).await {
+            let used = matches!(e.downcast_ref::<sqlx::Error>(),
+                Some(sqlx::Error::Database(e)) if e.is_unique_violation());
+            if used {
+                return Err(VoteError::DoubleNullifier(hex::encode(&action.nf)));
+            }
+            return Err(e.into());
+        }
+    }
+    if !store_ballot(&mut db_tx, id_election, height, &hash, &data).await? {
+        return Err(VoteError::DuplicateBallot(hex::encode(&hash)));
+    }
+    let cmxs = ballot
+        .data
+        .actions
+        .iter()
+        .map(|a| a.cmx.clone())
+        .collect::<Vec<_>>();
+    append_ballot_cmxs(&mut db_tx, id_election, height, &cmxs).await?;
+    db_tx.commit().await?;
+    Ok(hash)
+}
@@ -1,98 +1,68 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
+use futures::{stream::BoxStream, StreamExt as _};
 use orchard::{
     keys::{Diversifier, FullViewingKey, Scope},
     note::{Nullifier, RandomSeed, Rho},
     value::NoteValue,
+    vote::Frontier,
 };
 use pasta_curves::Fp;
 use serde::{Deserialize, Serialize};
-use sqlx::{sqlite::SqliteRow, Row, SqliteConnection};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteRow},
+    Connection as _, QueryBuilder, Row, Sqlite, SqliteConnection,
+};
+
+use crate::{as_byte256, election::Election, source::SyncOrigin, Hash};
 
-use crate::as_byte256;
+static MIGRATOR: Migrator = sqlx::migrate!();
 
+/// Open or create a database and bring its schema to the latest version
+pub async fn open_database(path: &str) -> Result<SqliteConnection> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    let mut connection = SqliteConnection::connect_with(&options).await?;
+    create_schema(&mut connection).await?;
+    Ok(connection)
+}
+
+/// Create the tables, or apply the migrations that are missing.
+/// Databases created before the schema was versioned are taken
+/// as version 1
 pub async fn create_schema(connection: &mut SqliteConnection) -> Result<()> {
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS properties(
-        id_property INTEGER PRIMARY KEY,
-        name TEXT NOT NULL UNIQUE,
-        value TEXT NOT NULL)",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS ballots(
-        id_ballot INTEGER PRIMARY KEY,
-        election INTEGER NOT NULL,
-        height INTEGER NOT NULL,
-        hash BLOB NOT NULL UNIQUE,
-        data BLOB NOT NULL)",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS nfs(
-        id_nf INTEGER PRIMARY KEY NOT NULL,
-        election INTEGER NOT NULL,
-        hash BLOB NOT NULL UNIQUE)",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS dnfs(
-        id_dnf INTEGER PRIMARY KEY NOT NULL,
-        election INTEGER NOT NULL,
-        hash BLOB NOT NULL UNIQUE)",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS cmxs(
-        id_cmx INTEGER PRIMARY KEY NOT NULL,
-        election INTEGER NOT NULL,
-        hash BLOB NOT NULL UNIQUE)",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS cmx_roots(
-        id_cmx_root INTEGER PRIMARY KEY,
-        election INTEGER NOT NULL,
-        height INTEGER NOT NULL,
-        hash BLOB NOT NULL,
-        CONSTRAINT u_cmx_roots UNIQUE (election, hash))",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS cmx_frontiers(
-        id_cmx_frontier INTEGER PRIMARY KEY,
-        election INTEGER NOT NULL,
-        height INTEGER NOT NULL,
-        frontier TEXT NOT NULL,
-        CONSTRAINT u_cmx_frontiers UNIQUE (election, height))",
-    )
-    .execute(&mut *connection)
-    .await?;
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS notes(
-        id_note INTEGER PRIMARY KEY,
-        election INTEGER NOT NULL,
-        scope INTEGER NOT NULL,
-        position INTEGER NOT NULL UNIQUE,
-        height INTEGER NOT NULL,
-        txid BLOB NOT NULL,
-        value INTEGER NOT NULL,
-        div BLOB NOT NULL,
-        rseed BLOB NOT NULL,
-        nf BLOB NOT NULL,
-        dnf BLOB NOT NULL,
-        rho BLOB NOT NULL,
-        spent INTEGER)",
+    let latest = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+    if let Some(version) = schema_version(connection).await? {
+        if version > latest {
+            anyhow::bail!(
+                "Database schema version {version} is newer than the supported version {latest}"
+            );
+        }
+    }
+    MIGRATOR.run(&mut *connection).await?;
+    Ok(())
+}
+
+/// Version of the last migration applied, None if the database
+/// has not been migrated yet
+pub async fn schema_version(connection: &mut SqliteConnection) -> Result<Option<i64>> {
+    let migrated: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master
+        WHERE type = 'table' AND name = '_sqlx_migrations')",
     )
-    .execute(&mut *connection)
+    .fetch_one(&mut *connection)
     .await?;
-
-    Ok(())
+    if !migrated {
+        return Ok(None);
+    }
+    let version: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&mut *connection)
+            .await?;
+    Ok(version)
 }
 
 pub async fn store_prop(connection: &mut SqliteConnection, name: &str, value: &str) -> Result<()> {
@@ -119,6 +89,128 @@ pub async fn load_prop(connection: &mut SqliteConnection, name: &str) -> Result<
     Ok(value)
 }
 
+/// Store an election, or update it if an election with the same id
+/// is already in the database. Returns the election id in the database
+pub async fn store_election(connection: &mut SqliteConnection, election: &Election) -> Result<u32> {
+    let data = serde_json::to_string(election)?;
+    let id_election: u32 = sqlx::query_scalar(
+        "INSERT INTO elections(id, data, height) VALUES (?, ?, NULL)
+        ON CONFLICT (id) DO UPDATE SET data = excluded.data
+        RETURNING id_election",
+    )
+    .bind(election.id())
+    .bind(&data)
+    .fetch_one(connection)
+    .await?;
+    Ok(id_election)
+}
+
+/// Attach an election definition to the data synced under election
+/// number `legacy_id` before elections had their own table, so that
+/// the sync resumes where it stopped. Returns the election id
+pub async fn adopt_legacy_election(
+    connection: &mut SqliteConnection,
+    legacy_id: u32,
+    election: &Election,
+) -> Result<u32> {
+    let data = serde_json::to_string(election)?;
+    let r = sqlx::query("UPDATE elections SET id = ?, data = ? WHERE id_election = ? AND id = ?")
+        .bind(election.id())
+        .bind(&data)
+        .bind(legacy_id)
+        .bind(format!("legacy-{legacy_id}"))
+        .execute(connection)
+        .await?;
+    if r.rows_affected() != 1 {
+        anyhow::bail!("No legacy data for election {legacy_id}");
+    }
+    Ok(legacy_id)
+}
+
+pub async fn load_election(connection: &mut SqliteConnection, id_election: u32) -> Result<Election> {
+    let data: Option<String> = sqlx::query_scalar("SELECT data FROM elections WHERE id_election = ?")
+        .bind(id_election)
+        .fetch_optional(connection)
+        .await?;
+    let data = data.ok_or_else(|| anyhow::anyhow!("Unknown election {id_election}"))?;
+    let election = serde_json::from_str(&data)?;
+    Ok(election)
+}
+
+/// Database id of the election with the given [`Election::id`]
+pub async fn find_election(connection: &mut SqliteConnection, id: &str) -> Result<Option<u32>> {
+    let id_election = sqlx::query_scalar("SELECT id_election FROM elections WHERE id = ?")
+        .bind(id)
+        .fetch_optional(connection)
+        .await?;
+    Ok(id_election)
+}
+
+pub async fn list_elections(connection: &mut SqliteConnection) -> Result<Vec<(u32, Election)>> {
+    // legacy elections have no definition yet
+    let elections: Vec<(u32, String)> = sqlx::query_as(
+        "SELECT id_election, data FROM elections
+        WHERE data <> '' ORDER BY id_election",
+    )
+    .fetch_all(connection)
+    .await?;
+    elections
+        .into_iter()
+        .map(|(id_election, data)| -> Result<(u32, Election)> {
+            Ok((id_election, serde_json::from_str(&data)?))
+        })
+        .collect()
+}
+
+/// Height of the last block stored for the election,
+/// None if the sync has not started
+pub async fn load_sync_height(connection: &mut SqliteConnection, id_election: u32) -> Result<Option<u32>> {
+    let height: Option<Option<u32>> = sqlx::query_scalar("SELECT height FROM elections WHERE id_election = ?")
+        .bind(id_election)
+        .fetch_optional(connection)
+        .await?;
+    let height = height.ok_or_else(|| anyhow::anyhow!("Unknown election {id_election}"))?;
+    Ok(height)
+}
+
+pub async fn store_sync_height(connection: &mut SqliteConnection, id_election: u32, height: u32) -> Result<()> {
+    sqlx::query("UPDATE elections SET height = ? WHERE id_election = ?")
+        .bind(height)
+        .bind(id_election)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+/// Record the server or the block archive the election
+/// data is synced from
+pub async fn store_election_source(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    origin: &SyncOrigin,
+) -> Result<()> {
+    sqlx::query("UPDATE elections SET server = ?, archive_hash = ? WHERE id_election = ?")
+        .bind(origin.server.as_deref())
+        .bind(origin.archive_hash.as_ref().map(|h| h.as_slice()))
+        .bind(id_election)
+        .execute(connection)
+        .await?;
+    Ok(())
+}
+
+pub async fn load_election_source(connection: &mut SqliteConnection, id_election: u32) -> Result<SyncOrigin> {
+    let source: Option<(Option<String>, Option<Vec<u8>>)> =
+        sqlx::query_as("SELECT server, archive_hash FROM elections WHERE id_election = ?")
+            .bind(id_election)
+            .fetch_optional(connection)
+            .await?;
+    let (server, archive_hash) = source.ok_or_else(|| anyhow::anyhow!("Unknown election {id_election}"))?;
+    Ok(SyncOrigin {
+        server,
+        archive_hash: archive_hash.map(|h| as_byte256(&h)),
+    })
+}
+
 pub async fn store_dnf(connection: &mut SqliteConnection, id_election: u32, dnf: &[u8]) -> Result<()> {
     sqlx::query("INSERT INTO dnfs(election, hash) VALUES (?, ?)")
         .bind(id_election)
@@ -131,6 +223,7 @@ pub async fn store_dnf(connection: &mut SqliteConnection, id_election: u32, dnf:
 pub async fn store_note(
     connection: &mut SqliteConnection,
     id_election: u32,
+    account: u32,
     domain: Fp,
     fvk: &FullViewingKey,
     scope: u8,
@@ -147,10 +240,11 @@ pub async fn store_note(
     let rho = note.rho().to_bytes();
     let r = sqlx::query(
         "INSERT INTO notes
-        (election, scope, position, height, txid, value, div, rseed, nf, dnf, rho, spent)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, NULL)",
+        (election, account, scope, position, height, txid, value, div, rseed, nf, dnf, rho, spent)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, NULL)",
     )
     .bind(id_election)
+    .bind(account)
     .bind(scope)
     .bind(position)
     .bind(height)
@@ -176,16 +270,36 @@ pub async fn mark_spent(connection: &mut SqliteConnection, id: u32, height: u32)
     Ok(())
 }
 
+/// Map the nullifiers of the unspent notes to their note id,
+/// so that spends can be detected when a sync is resumed
+pub async fn list_unspent_nfs(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> Result<HashMap<Hash, u32>> {
+    let nfs = sqlx::query("SELECT id_note, nf FROM notes WHERE spent IS NULL AND election = ?")
+        .bind(id_election)
+        .map(|row: SqliteRow| {
+            let id_note: u32 = row.get(0);
+            let nf: Vec<u8> = row.get(1);
+            (as_byte256(&nf), id_note)
+        })
+        .fetch_all(connection)
+        .await?;
+    Ok(nfs.into_iter().collect())
+}
+
 pub async fn list_notes(
     connection: &mut SqliteConnection,
     id_election: u32,
+    account: u32,
     fvk: &FullViewingKey,
 ) -> Result<Vec<(orchard::Note, u32)>> {
     let notes = sqlx::query(
         "SELECT scope, position, height, txid, value, div, rseed, nf, dnf, rho
-        FROM notes WHERE spent IS NULL AND election = ?",
+        FROM notes WHERE spent IS NULL AND election = ? AND account = ?",
     )
     .bind(id_election)
+    .bind(account)
     .map(|row: SqliteRow| {
         let scope: u8 = row.get(0);
         let position: u32 = row.get(1);
@@ -223,15 +337,133 @@ pub async fn list_notes(
     Ok(notes)
 }
 
-pub async fn store_cmx(connection: &mut SqliteConnection, id_election: u32, cmx: &[u8]) -> Result<()> {
-    sqlx::query("INSERT INTO cmxs(election, hash) VALUES (?, ?)")
+pub async fn store_cmx(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    cmx: &[u8],
+) -> Result<()> {
+    sqlx::query("INSERT INTO cmxs(election, height, hash) VALUES (?, ?, ?)")
         .bind(id_election)
+        .bind(height)
         .bind(cmx)
         .execute(&mut *connection)
         .await?;
     Ok(())
 }
 
+pub async fn store_block(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    hash: &[u8],
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO blocks(election, height, hash) VALUES (?, ?, ?)
+        ON CONFLICT (election, height) DO UPDATE SET hash = excluded.hash",
+    )
+    .bind(id_election)
+    .bind(height)
+    .bind(hash)
+    .execute(connection)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_block_hash(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+) -> Result<Option<Vec<u8>>> {
+    let hash = sqlx::query("SELECT hash FROM blocks WHERE election = ? AND height = ?")
+        .bind(id_election)
+        .bind(height)
+        .map(|row: SqliteRow| {
+            let hash: Vec<u8> = row.get(0);
+            hash
+        })
+        .fetch_optional(connection)
+        .await?;
+    Ok(hash)
+}
+
+/// Remove every block, nullifier, commitment and note after `height`
+/// and restore the notes that were spent after it
+pub async fn rollback_to(connection: &mut SqliteConnection, id_election: u32, height: u32) -> Result<()> {
+    // all or nothing, a partial rollback would resume the sync
+    // from a height whose data is gone
+    let mut db_tx = connection.begin().await?;
+    for table in ["nfs", "cmxs", "blocks"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE election = ? AND height > ?"))
+            .bind(id_election)
+            .bind(height)
+            .execute(&mut *db_tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM notes WHERE election = ? AND height > ?")
+        .bind(id_election)
+        .bind(height)
+        .execute(&mut *db_tx)
+        .await?;
+    sqlx::query("UPDATE notes SET spent = NULL WHERE election = ? AND spent > ?")
+        .bind(id_election)
+        .bind(height)
+        .execute(&mut *db_tx)
+        .await?;
+    store_sync_height(&mut db_tx, id_election, height).await?;
+    db_tx.commit().await?;
+    Ok(())
+}
+
+pub async fn count_cmxs(connection: &mut SqliteConnection, id_election: u32) -> Result<u32> {
+    let (count,): (u32,) = sqlx::query_as("SELECT COUNT(*) FROM cmxs WHERE election = ?")
+        .bind(id_election)
+        .fetch_one(connection)
+        .await?;
+    Ok(count)
+}
+
+pub async fn store_nfs(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    nfs: &[Vec<u8>],
+) -> Result<()> {
+    store_hashes(connection, "nfs", id_election, height, nfs).await
+}
+
+pub async fn store_cmxs(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    cmxs: &[Vec<u8>],
+) -> Result<()> {
+    store_hashes(connection, "cmxs", id_election, height, cmxs).await
+}
+
+// Stay well below the limit on the number of bound parameters
+const ROWS_PER_INSERT: usize = 1000;
+
+async fn store_hashes(
+    connection: &mut SqliteConnection,
+    table: &str,
+    id_election: u32,
+    height: u32,
+    hashes: &[Vec<u8>],
+) -> Result<()> {
+    for chunk in hashes.chunks(ROWS_PER_INSERT) {
+        let mut query: QueryBuilder<Sqlite> =
+            QueryBuilder::new(format!("INSERT INTO {table}(election, height, hash) "));
+        query.push_values(chunk, |mut row, hash| {
+            row.push_bind(id_election)
+                .push_bind(height)
+                .push_bind(hash.as_slice());
+        });
+        query.build().execute(&mut *connection).await?;
+    }
+    Ok(())
+}
+
 pub async fn store_cmx_root(
     connection: &mut SqliteConnection,
     id_election: u32,
@@ -241,7 +473,8 @@ pub async fn store_cmx_root(
     sqlx::query(
         "INSERT INTO cmx_roots
         (election, height, hash)
-        VALUES (?, ?, ?)",
+        VALUES (?, ?, ?)
+        ON CONFLICT DO NOTHING",
     )
     .bind(id_election)
     .bind(height)
@@ -251,6 +484,184 @@ pub async fn store_cmx_root(
     Ok(())
 }
 
+pub async fn store_cmx_frontier(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    frontier: &Frontier,
+) -> Result<()> {
+    let frontier = serde_json::to_string(frontier)?;
+    sqlx::query(
+        "INSERT INTO cmx_frontiers
+        (election, height, frontier)
+        VALUES (?, ?, ?)
+        ON CONFLICT (election, height) DO UPDATE SET frontier = excluded.frontier",
+    )
+    .bind(id_election)
+    .bind(height)
+    .bind(&frontier)
+    .execute(&mut *connection)
+    .await?;
+    Ok(())
+}
+
+/// Latest frontier of the ballot commitment tree and its height
+pub async fn load_cmx_frontier(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> Result<Option<(u32, Frontier)>> {
+    let frontier: Option<(u32, String)> = sqlx::query_as(
+        "SELECT height, frontier FROM cmx_frontiers
+        WHERE election = ? ORDER BY height DESC LIMIT 1",
+    )
+    .bind(id_election)
+    .fetch_optional(connection)
+    .await?;
+    let frontier = frontier
+        .map(|(height, frontier)| -> Result<(u32, Frontier)> {
+            Ok((height, serde_json::from_str(&frontier)?))
+        })
+        .transpose()?;
+    Ok(frontier)
+}
+
+/// Height of the last root or frontier saved for the ballot tree
+pub async fn load_ballot_tree_height(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> Result<Option<u32>> {
+    let height: Option<u32> = sqlx::query_scalar(
+        "SELECT MAX(height) FROM (
+        SELECT height FROM cmx_roots WHERE election = ?1
+        UNION ALL SELECT height FROM cmx_frontiers WHERE election = ?1)",
+    )
+    .bind(id_election)
+    .fetch_one(connection)
+    .await?;
+    Ok(height)
+}
+
+/// Root of the ballot commitment tree at `height`
+pub async fn load_cmx_root(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+) -> Result<Option<Vec<u8>>> {
+    let root = sqlx::query_scalar(
+        "SELECT hash FROM cmx_roots
+        WHERE election = ? AND height <= ? ORDER BY height DESC, id_cmx_root DESC LIMIT 1",
+    )
+    .bind(id_election)
+    .bind(height)
+    .fetch_optional(connection)
+    .await?;
+    Ok(root)
+}
+
+/// Store a serialized ballot. Returns false if the election
+/// already has a ballot with the same hash
+pub async fn store_ballot(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    hash: &[u8],
+    data: &[u8],
+) -> Result<bool> {
+    let r = sqlx::query(
+        "INSERT INTO ballots(election, height, hash, data) VALUES (?, ?, ?, ?)
+        ON CONFLICT (election, hash) DO NOTHING",
+    )
+    .bind(id_election)
+    .bind(height)
+    .bind(hash)
+    .bind(data)
+    .execute(connection)
+    .await?;
+    Ok(r.rows_affected() == 1)
+}
+
+pub async fn get_ballot(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    hash: &[u8],
+) -> Result<Option<BallotRecord>> {
+    let ballot = sqlx::query(
+        "SELECT id_ballot, height, hash, data FROM ballots
+        WHERE election = ? AND hash = ?",
+    )
+    .bind(id_election)
+    .bind(hash)
+    .map(BallotRecord::from_row)
+    .fetch_optional(connection)
+    .await?;
+    Ok(ballot)
+}
+
+/// Ballots of the election in insertion order, `limit` ballots
+/// starting at `offset`
+pub async fn list_ballots(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    offset: u32,
+    limit: u32,
+) -> Result<Vec<BallotRecord>> {
+    let ballots = sqlx::query(
+        "SELECT id_ballot, height, hash, data FROM ballots
+        WHERE election = ? ORDER BY id_ballot LIMIT ? OFFSET ?",
+    )
+    .bind(id_election)
+    .bind(limit)
+    .bind(offset)
+    .map(BallotRecord::from_row)
+    .fetch_all(connection)
+    .await?;
+    Ok(ballots)
+}
+
+pub async fn count_ballots(connection: &mut SqliteConnection, id_election: u32) -> Result<u32> {
+    let count: u32 = sqlx::query_scalar("SELECT COUNT(*) FROM ballots WHERE election = ?")
+        .bind(id_election)
+        .fetch_one(connection)
+        .await?;
+    Ok(count)
+}
+
+/// All the ballots of the election in insertion order,
+/// without loading them in memory
+pub fn stream_ballots(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> BoxStream<'_, Result<BallotRecord>> {
+    sqlx::query(
+        "SELECT id_ballot, height, hash, data FROM ballots
+        WHERE election = ? ORDER BY id_ballot",
+    )
+    .bind(id_election)
+    .map(BallotRecord::from_row)
+    .fetch(connection)
+    .map(|r| r.map_err(anyhow::Error::from))
+    .boxed()
+}
+
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct BallotRecord {
+    pub id_ballot: u32,
+    pub height: u32,
+    pub hash: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl BallotRecord {
+    fn from_row(row: SqliteRow) -> Self {
+        BallotRecord {
+            id_ballot: row.get(0),
+            height: row.get(1),
+            hash: row.get(2),
+            data: row.get(3),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Default, Debug)]
 pub struct Note {
     pub position: u32,
@@ -280,3 +691,160 @@ impl Note {
         (note, self.position)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Schema created by `create_schema` before migrations, frozen so that
+    // later edits to the migrations cannot change what is being upgraded
+    const BASELINE: &str = "
+    CREATE TABLE IF NOT EXISTS properties(
+        id_property INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS ballots(
+        id_ballot INTEGER PRIMARY KEY,
+        election INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        hash BLOB NOT NULL UNIQUE,
+        data BLOB NOT NULL);
+    CREATE TABLE IF NOT EXISTS nfs(
+        id_nf INTEGER PRIMARY KEY NOT NULL,
+        election INTEGER NOT NULL,
+        hash BLOB NOT NULL UNIQUE);
+    CREATE TABLE IF NOT EXISTS dnfs(
+        id_dnf INTEGER PRIMARY KEY NOT NULL,
+        election INTEGER NOT NULL,
+        hash BLOB NOT NULL UNIQUE);
+    CREATE TABLE IF NOT EXISTS cmxs(
+        id_cmx INTEGER PRIMARY KEY NOT NULL,
+        election INTEGER NOT NULL,
+        hash BLOB NOT NULL UNIQUE);
+    CREATE TABLE IF NOT EXISTS cmx_roots(
+        id_cmx_root INTEGER PRIMARY KEY,
+        election INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        hash BLOB NOT NULL,
+        CONSTRAINT u_cmx_roots UNIQUE (election, hash));
+    CREATE TABLE IF NOT EXISTS cmx_frontiers(
+        id_cmx_frontier INTEGER PRIMARY KEY,
+        election INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        frontier TEXT NOT NULL,
+        CONSTRAINT u_cmx_frontiers UNIQUE (election, height));
+    CREATE TABLE IF NOT EXISTS notes(
+        id_note INTEGER PRIMARY KEY,
+        election INTEGER NOT NULL,
+        scope INTEGER NOT NULL,
+        position INTEGER NOT NULL UNIQUE,
+        height INTEGER NOT NULL,
+        txid BLOB NOT NULL,
+        value INTEGER NOT NULL,
+        div BLOB NOT NULL,
+        rseed BLOB NOT NULL,
+        nf BLOB NOT NULL,
+        dnf BLOB NOT NULL,
+        rho BLOB NOT NULL,
+        spent INTEGER);";
+
+    async fn baseline() -> SqliteConnection {
+        let mut connection = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::raw_sql(BASELINE).execute(&mut connection).await.unwrap();
+        connection
+    }
+
+    fn latest_version() -> i64 {
+        MIGRATOR.iter().map(|m| m.version).max().unwrap()
+    }
+
+    #[tokio::test]
+    async fn new_database_is_at_latest_version() {
+        let mut connection = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        assert_eq!(schema_version(&mut connection).await.unwrap(), None);
+        create_schema(&mut connection).await.unwrap();
+        create_schema(&mut connection).await.unwrap();
+        assert_eq!(schema_version(&mut connection).await.unwrap(), Some(latest_version()));
+    }
+
+    #[tokio::test]
+    async fn upgrade_keeps_synced_data() {
+        let mut connection = baseline().await;
+        store_prop(&mut connection, "height", "2500").await.unwrap();
+        sqlx::query("INSERT INTO nfs(election, hash) VALUES (1, ?)")
+            .bind(&[1u8; 32][..])
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO cmxs(election, hash) VALUES (1, ?)")
+            .bind(&[2u8; 32][..])
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        // notes were not tagged with their election
+        sqlx::query(
+            "INSERT INTO notes
+            (election, scope, position, height, txid, value, div, rseed, nf, dnf, rho)
+            VALUES (0, 0, 0, 2400, ?1, 1000, ?1, ?1, ?1, ?1, ?1)",
+        )
+        .bind(&[3u8; 32][..])
+        .execute(&mut connection)
+        .await
+        .unwrap();
+
+        create_schema(&mut connection).await.unwrap();
+        assert_eq!(schema_version(&mut connection).await.unwrap(), Some(latest_version()));
+        assert_eq!(count_cmxs(&mut connection, 1).await.unwrap(), 1);
+        let notes: Vec<(u32,)> = sqlx::query_as("SELECT election FROM notes")
+            .fetch_all(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(notes, vec![(1,)]);
+        let nfs: u32 = sqlx::query_scalar("SELECT COUNT(*) FROM nfs WHERE election = 1")
+            .fetch_one(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(nfs, 1);
+        assert_eq!(load_sync_height(&mut connection, 1).await.unwrap(), Some(2500));
+        assert!(list_elections(&mut connection).await.unwrap().is_empty());
+
+        let election = Election::default();
+        let id_election = adopt_legacy_election(&mut connection, 1, &election).await.unwrap();
+        assert_eq!(id_election, 1);
+        assert_eq!(find_election(&mut connection, &election.id()).await.unwrap(), Some(1));
+        assert_eq!(load_sync_height(&mut connection, 1).await.unwrap(), Some(2500));
+        assert_eq!(list_elections(&mut connection).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn new_elections_do_not_reuse_legacy_ids() {
+        let mut connection = baseline().await;
+        sqlx::query("INSERT INTO cmxs(election, hash) VALUES (1, ?)")
+            .bind(&[2u8; 32][..])
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        create_schema(&mut connection).await.unwrap();
+
+        let id_election = store_election(&mut connection, &Election::default()).await.unwrap();
+        assert_ne!(id_election, 1);
+        assert_eq!(load_sync_height(&mut connection, id_election).await.unwrap(), None);
+        assert_eq!(count_cmxs(&mut connection, id_election).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn newer_schema_is_rejected() {
+        let mut connection = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        create_schema(&mut connection).await.unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations
+            (version, description, success, checksum, execution_time)
+            VALUES (?, 'future', TRUE, x'00', 0)",
+        )
+        .bind(latest_version() + 1)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+        assert!(create_schema(&mut connection).await.is_err());
+    }
+}
@@ -7,7 +7,7 @@ use orchard::{
     Note,
 };
 use zcash_address::unified::{self, Container, Encoding, Fvk};
-use zcash_note_encryption::{try_compact_note_decryption, EphemeralKeyBytes};
+use zcash_note_encryption::{batch, try_compact_note_decryption, EphemeralKeyBytes};
 use zcash_primitives::zip32::AccountId;
 
 use crate::{as_byte256, rpc::CompactOrchardAction};
@@ -42,6 +42,29 @@ pub fn try_decrypt(
     ivk: &PreparedIncomingViewingKey,
     action: &CompactOrchardAction,
 ) -> Result<Option<Note>> {
+    let (domain, action) = to_compact_action(action);
+    let note = try_compact_note_decryption(&domain, ivk, &action).map(|na| na.0);
+    Ok(note)
+}
+
+/// Trial decrypt every action with every key in a single batch.
+/// Returns, for each action, the note and the index of the key that decrypted it
+pub fn try_decrypt_batch(
+    ivks: &[PreparedIncomingViewingKey],
+    actions: &[CompactOrchardAction],
+) -> Result<Vec<Option<(Note, usize)>>> {
+    if ivks.is_empty() {
+        return Ok(vec![None; actions.len()]);
+    }
+    let outputs = actions.iter().map(to_compact_action).collect::<Vec<_>>();
+    let notes = batch::try_compact_note_decryption(ivks, &outputs)
+        .into_iter()
+        .map(|r| r.map(|((note, _), ivk_idx)| (note, ivk_idx)))
+        .collect();
+    Ok(notes)
+}
+
+fn to_compact_action(action: &CompactOrchardAction) -> (OrchardDomain, CompactAction) {
     let CompactOrchardAction {
         nullifier,
         cmx,
@@ -58,6 +81,5 @@ pub fn try_decrypt(
         EphemeralKeyBytes(as_byte256(ephemeral_key)),
         ciphertext.clone().try_into().unwrap(),
     );
-    let note = try_compact_note_decryption(&domain, ivk, &action).map(|na| na.0);
-    Ok(note)
+    (domain, action)
 }
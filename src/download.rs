@@ -1,147 +1,759 @@
 use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use orchard::keys::{FullViewingKey, PreparedIncomingViewingKey, Scope};
+use futures::StreamExt as _;
+use orchard::{
+    keys::{FullViewingKey, PreparedIncomingViewingKey, Scope},
+    Note,
+};
 use pasta_curves::Fp;
-use sqlx::SqliteConnection;
-use tonic::{transport::Endpoint, Request};
+use sqlx::{Connection as _, SqliteConnection};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tonic::Status;
 
 use crate::as_byte256;
-use crate::db::{mark_spent, store_prop};
+use crate::db::{
+    count_cmxs, get_block_hash, list_unspent_nfs, load_sync_height, mark_spent, rollback_to,
+    store_block, store_cmxs, store_election_source, store_nfs, store_sync_height,
+};
 use crate::{
     db::store_note,
-    decrypt::try_decrypt,
+    decrypt::try_decrypt_batch,
     election::Election,
-    rpc::{compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, CompactBlock},
+    errors::VoteError,
+    rpc::CompactBlock,
+    source::{BlockSource, BlockStream},
     Result,
 };
 
+// Number of actions (plus blocks) written per database transaction
+const BATCH_SIZE: usize = 10_000;
+// Number of blocks trial decrypted together by a worker
+const BLOCKS_PER_CHUNK: usize = 100;
+// Number of decrypted chunks waiting for the database
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Exponential backoff between the attempts to resume a sync
+/// after a transient network error
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of retries in a row without any new block, before giving up
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+/// How a sync retries after network errors, gets cancelled
+/// and reports its progress
+pub struct SyncOptions<P> {
+    pub retry_policy: RetryPolicy,
+    pub cancel: CancellationToken,
+    pub progress: P,
+}
+
+impl<P: Fn(SyncEvent) + Send + 'static> SyncOptions<P> {
+    /// Default retry policy and a token that is never cancelled
+    pub fn new(progress: P) -> Self {
+        SyncOptions {
+            retry_policy: RetryPolicy::default(),
+            cancel: CancellationToken::new(),
+            progress,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SyncEvent {
+    Progress(SyncProgress),
+    /// The sync failed and will resume after `height` in `delay`
+    Retry {
+        attempt: u32,
+        height: u32,
+        delay: Duration,
+        error: String,
+    },
+}
+
+/// Progress of a sync, reported after every chunk of blocks.
+/// Counters start at zero with every call to [`download_reference_data`]
+#[derive(Clone, Debug)]
+pub struct SyncProgress {
+    /// Blocks are stored up to this height
+    pub height: u32,
+    pub end_height: u32,
+    pub blocks_per_second: f64,
+    pub actions: u64,
+    pub notes: u64,
+    pub spent_notes: u64,
+    pub eta: Option<Duration>,
+}
+
+struct SyncStats {
+    started: Instant,
+    start_height: u32,
+    end_height: u32,
+    actions: u64,
+    notes: u64,
+    spent_notes: u64,
+}
+
+impl SyncStats {
+    fn new(start_height: u32, end_height: u32) -> Self {
+        SyncStats {
+            started: Instant::now(),
+            start_height,
+            end_height,
+            actions: 0,
+            notes: 0,
+            spent_notes: 0,
+        }
+    }
+
+    fn add(&mut self, counts: &BlockCounts) {
+        self.actions += counts.actions as u64;
+        self.notes += counts.notes as u64;
+        self.spent_notes += counts.spent_notes as u64;
+    }
+
+    fn progress(&self, height: u32) -> SyncProgress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let blocks = height.saturating_sub(self.start_height) as f64;
+        let blocks_per_second = if elapsed > 0.0 { blocks / elapsed } else { 0.0 };
+        let remaining = self.end_height.saturating_sub(height) as f64;
+        let eta = (blocks_per_second > 0.0)
+            .then(|| Duration::from_secs_f64(remaining / blocks_per_second));
+        SyncProgress {
+            height,
+            end_height: self.end_height,
+            blocks_per_second,
+            actions: self.actions,
+            notes: self.notes,
+            spent_notes: self.spent_notes,
+            eta,
+        }
+    }
+}
+
+/// Download the nullifiers and note commitments of the election range
+/// and scan them for the notes of every account in `fvks`. Accounts
+/// are given as pairs of account id and viewing key.
+///
+/// The election must have been added with [`crate::db::store_election`].
+/// The sync resumes from the last height stored in the database, so an
+/// interrupted download can be restarted without clearing it.
+/// Transient network errors are retried according to the retry policy
+/// of `options`, from the last committed block.
+///
+/// When the `cancel` token of `options` is triggered, the sync commits
+/// the blocks handled so far and fails with [`VoteError::Cancelled`],
+/// even while waiting on the server. It can be resumed later.
 pub async fn download_reference_data(
     connection: &mut SqliteConnection,
     id_election: u32,
     election: &Election,
-    fvk: Option<FullViewingKey>,
-    lwd_url: &str,
-    progress: impl Fn(u32) + Send + 'static,
+    fvks: &[(u32, FullViewingKey)],
+    source: &mut impl BlockSource,
+    options: &SyncOptions<impl Fn(SyncEvent) + Send + 'static>,
 ) -> Result<u32> {
-    let pivks = fvk.clone().map(|fvk| {
-        let ivk = fvk.to_ivk(Scope::External);
-        let pivk1 = PreparedIncomingViewingKey::new(&ivk);
-        let ivk = fvk.to_ivk(Scope::Internal);
-        let pivk2 = PreparedIncomingViewingKey::new(&ivk);
-        (pivk1, pivk2)
-    });
+    let SyncOptions {
+        retry_policy,
+        cancel,
+        progress,
+    } = options;
+    // Two keys per account, external then internal, so that
+    // key index = 2 * account index + scope
+    let pivks = fvks
+        .iter()
+        .flat_map(|(_, fvk)| {
+            [Scope::External, Scope::Internal]
+                .map(|scope| PreparedIncomingViewingKey::new(&fvk.to_ivk(scope)))
+        })
+        .collect::<Vec<_>>();
+    let pivks = Arc::new(pivks);
     let domain = election.domain();
     let start = election.start_height as u64;
     let end = election.end_height as u64;
-    let lwd_url = lwd_url.to_string();
-
-    let ep = Endpoint::from_shared(lwd_url)?;
-    let mut client = CompactTxStreamerClient::connect(ep).await?;
-    let mut blocks = client
-        .get_block_range(Request::new(BlockRange {
-            start: Some(BlockId {
-                height: start + 1,
-                hash: vec![],
-            }),
-            end: Some(BlockId {
-                height: end,
-                hash: vec![],
-            }),
-            spam_filter_threshold: 0,
-        }))
-        .await?
-        .into_inner();
-    let mut position = 0usize;
-    let mut nfs_cache = HashMap::new();
-    while let Some(block) = blocks.message().await? {
-        let height = block.height as u32;
-        if height % 1000 == 0 || height == end as u32 {
-            progress(block.height as u32);
+
+    let synced = load_sync_height(connection, id_election).await?;
+    let mut start = synced.map_or(start, |h| (h as u64).max(start));
+    if start >= end {
+        return Ok(end as u32);
+    }
+    // the source that completes the sync is the one recorded
+    store_election_source(connection, id_election, &source.origin()).await?;
+
+    let mut stats = SyncStats::new(start as u32, end as u32);
+    let mut attempt = 0u32;
+    'sync: while start < end {
+        let blocks = match source.get_block_range(start as u32 + 1, end as u32).await {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                backoff(retry_policy, cancel, &mut attempt, start as u32, e, progress).await?;
+                continue;
+            }
+        };
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(decrypt_blocks(blocks, pivks.clone(), sender));
+
+        let mut position = count_cmxs(connection, id_election).await? as usize;
+        let mut nfs_cache = list_unspent_nfs(connection, id_election).await?;
+        let mut db_tx = connection.begin().await?;
+        let mut batch_size = 0usize;
+        loop {
+            // do not wait for a stalled server to notice the cancellation
+            let decrypted_blocks = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => {
+                    db_tx.commit().await?;
+                    return Err(VoteError::Cancelled(start as u32));
+                }
+                decrypted_blocks = receiver.recv() => decrypted_blocks,
+            };
+            let Some(decrypted_blocks) = decrypted_blocks else {
+                break;
+            };
+            let decrypted_blocks = match decrypted_blocks {
+                Ok(decrypted_blocks) => decrypted_blocks,
+                Err(e) => {
+                    // the blocks handled so far are complete
+                    db_tx.commit().await?;
+                    backoff(retry_policy, cancel, &mut attempt, start as u32, e, progress).await?;
+                    continue 'sync;
+                }
+            };
+            for decrypted_block in decrypted_blocks {
+                if cancel.is_cancelled() {
+                    db_tx.commit().await?;
+                    return Err(VoteError::Cancelled(start as u32));
+                }
+                let block = &decrypted_block.block;
+                let height = block.height as u32;
+                if let Some(prev_hash) = get_block_hash(&mut db_tx, id_election, height - 1).await? {
+                    if prev_hash != block.prev_hash {
+                        db_tx.commit().await?;
+                        let fork_height = tokio::select! {
+                            _ = cancel.cancelled() => {
+                                return Err(VoteError::Cancelled(start as u32));
+                            }
+                            fork_height = find_fork_height(
+                                connection,
+                                source,
+                                id_election,
+                                election.start_height,
+                                height - 1,
+                            ) => fork_height?,
+                        };
+                        if fork_height == height - 1 {
+                            // our blocks are on the server chain, the range it sent is not
+                            let e = VoteError::InconsistentChain(height);
+                            if !source.is_remote() {
+                                return Err(e);
+                            }
+                            backoff(retry_policy, cancel, &mut attempt, start as u32, e, progress).await?;
+                            continue 'sync;
+                        }
+                        log::warn!("Reorg detected at {height}, rolling back to {fork_height}");
+                        rollback_to(connection, id_election, fork_height).await?;
+                        start = fork_height as u64;
+                        continue 'sync;
+                    }
+                }
+                let counts = handle_block(
+                    &mut db_tx,
+                    id_election,
+                    domain,
+                    fvks,
+                    position,
+                    decrypted_block,
+                    &mut nfs_cache,
+                ).await?;
+                stats.add(&counts);
+                position += counts.actions;
+                start = height as u64;
+                attempt = 0;
+                // count empty blocks too, so that the height gets committed regularly
+                batch_size += counts.actions + 1;
+                if batch_size >= BATCH_SIZE {
+                    db_tx.commit().await?;
+                    db_tx = connection.begin().await?;
+                    batch_size = 0;
+                }
+            }
+            progress(SyncEvent::Progress(stats.progress(start as u32)));
+        }
+        db_tx.commit().await?;
+        if start < end {
+            if !source.is_remote() {
+                return Err(VoteError::MissingBlocks(start as u32));
+            }
+            let e = Status::aborted(format!("Block stream ended at {start}"));
+            backoff(retry_policy, cancel, &mut attempt, start as u32, e.into(), progress).await?;
         }
-        let inc_position = handle_block(
-            connection,
-            id_election,
-            domain,
-            fvk.as_ref(),
-            pivks.as_ref(),
-            position,
-            block,
-            &mut nfs_cache,
-        ).await?;
-        position += inc_position;
     }
     Ok(end as u32)
 }
 
+/// Wait before the next attempt, or give up if the error is not
+/// transient, there were too many attempts or the sync is cancelled
+async fn backoff(
+    retry_policy: &RetryPolicy,
+    cancel: &CancellationToken,
+    attempt: &mut u32,
+    height: u32,
+    error: VoteError,
+    progress: &impl Fn(SyncEvent),
+) -> Result<()> {
+    if !error.is_transient() || *attempt >= retry_policy.max_retries {
+        return Err(error);
+    }
+    let delay = retry_policy.delay(*attempt);
+    *attempt += 1;
+    log::warn!("Sync failed at {height}: {error}, retry #{attempt} in {delay:?}");
+    progress(SyncEvent::Retry {
+        attempt: *attempt,
+        height,
+        delay,
+        error: error.to_string(),
+    });
+    if tokio::time::timeout(delay, cancel.cancelled()).await.is_ok() {
+        return Err(VoteError::Cancelled(height));
+    }
+    Ok(())
+}
+
+/// Walk back from `height` until the block we stored matches the
+/// server chain. Returns the height of the last common block, or the
+/// election start height if none of the stored blocks are on the chain
+async fn find_fork_height(
+    connection: &mut SqliteConnection,
+    source: &mut impl BlockSource,
+    id_election: u32,
+    start_height: u32,
+    height: u32,
+) -> Result<u32> {
+    let mut height = height;
+    while height > start_height {
+        let block = source.get_block(height).await?;
+        let hash = get_block_hash(connection, id_election, height).await?;
+        if hash.as_ref() == Some(&block.hash) {
+            break;
+        }
+        height -= 1;
+    }
+    Ok(height)
+}
+
+struct DecryptedBlock {
+    block: CompactBlock,
+    // For every action of the block, the note and the index
+    // of the key that decrypted it if it was ours
+    notes: Vec<Option<(Note, usize)>>,
+}
+
+/// Decryption stage: trial decrypt chunks of blocks on the blocking
+/// thread pool and pass them, in order, to the storage stage
+async fn decrypt_blocks(
+    blocks: BlockStream,
+    pivks: Arc<Vec<PreparedIncomingViewingKey>>,
+    sender: mpsc::Sender<Result<Vec<DecryptedBlock>>>,
+) {
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut chunks = pin!(blocks
+        .chunks(BLOCKS_PER_CHUNK)
+        .map(move |chunk| {
+            let pivks = pivks.clone();
+            tokio::task::spawn_blocking(move || decrypt_chunk(&pivks, chunk))
+        })
+        .buffered(workers));
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk
+            .map_err(|e| VoteError::from(anyhow::Error::from(e)))
+            .and_then(|chunk| chunk);
+        let failed = chunk.is_err();
+        // stop if the storage stage is gone or after the first error
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+fn decrypt_chunk(
+    pivks: &[PreparedIncomingViewingKey],
+    chunk: Vec<Result<CompactBlock>>,
+) -> Result<Vec<DecryptedBlock>> {
+    let blocks = chunk.into_iter().collect::<Result<Vec<_>>>()?;
+    let actions = blocks
+        .iter()
+        .flat_map(|b| b.vtx.iter().flat_map(|tx| tx.actions.iter().cloned()))
+        .collect::<Vec<_>>();
+    let mut notes = try_decrypt_batch(pivks, &actions)?.into_iter();
+    let blocks = blocks
+        .into_iter()
+        .map(|block| {
+            let n_actions = block.vtx.iter().map(|tx| tx.actions.len()).sum::<usize>();
+            let notes = notes.by_ref().take(n_actions).collect();
+            DecryptedBlock { block, notes }
+        })
+        .collect();
+    Ok(blocks)
+}
+
+struct BlockCounts {
+    actions: usize,
+    notes: usize,
+    spent_notes: usize,
+}
+
+/// Store the nullifiers, commitments and notes of a block. All the writes
+/// belong to the transaction of the current batch, so that a block is
+/// either fully stored or not at all
 async fn handle_block(
     connection: &mut SqliteConnection,
     id_election: u32,
     domain: Fp,
-    fvk: Option<&FullViewingKey>,
-    pivks: Option<&(PreparedIncomingViewingKey, PreparedIncomingViewingKey)>,
+    fvks: &[(u32, FullViewingKey)],
     start_position: usize,
-    block: CompactBlock,
+    decrypted_block: DecryptedBlock,
     nfs_cache: &mut HashMap<[u8; 32], u32>,
-) -> Result<usize> {
-    let mut position = 0usize;
+) -> Result<BlockCounts> {
+    let DecryptedBlock { block, notes } = decrypted_block;
+    let mut n_notes = 0;
+    let mut n_spent = 0;
+    let height = block.height as u32;
+    let mut notes = notes.into_iter();
+    let mut nfs = vec![];
+    let mut cmxs = vec![];
     for tx in block.vtx {
-        let height = block.height;
         for a in tx.actions {
-            if let Some((pivk1, pivk2)) = pivks {
-                let fvk = fvk.unwrap(); // if we have pivk, we have fvk
-                let p = start_position + position;
-                let txid = &tx.hash;
-
-                if let Some(note) = try_decrypt(pivk1, &a)? {
-                    let id = store_note(
-                        connection,
-                        0,
-                        domain,
-                        fvk,
-                        0,
-                        height as u32,
-                        p as u32,
-                        txid,
-                        &note,
-                    ).await?;
-                    nfs_cache.insert(note.nullifier(fvk).to_bytes(), id);
-                }
-                if let Some(note) = try_decrypt(pivk2, &a)? {
-                    let id = store_note(
-                        connection,
-                        0,
-                        domain,
-                        fvk,
-                        1,
-                        height as u32,
-                        p as u32,
-                        txid,
-                        &note,
-                    ).await?;
-                    nfs_cache.insert(note.nullifier(fvk).to_bytes(), id);
-                }
+            if let Some((note, key_idx)) = notes.next().flatten() {
+                let (account, fvk) = &fvks[key_idx / 2];
+                let p = start_position + cmxs.len();
+                let id = store_note(
+                    connection,
+                    id_election,
+                    *account,
+                    domain,
+                    fvk,
+                    (key_idx % 2) as u8,
+                    height,
+                    p as u32,
+                    &tx.hash,
+                    &note,
+                ).await?;
+                nfs_cache.insert(note.nullifier(fvk).to_bytes(), id);
+                n_notes += 1;
             }
-            let nf = &a.nullifier;
-            let cmx = &a.cmx;
-
-            sqlx::query("INSERT INTO nfs(election, hash) VALUES (?1, ?2)")
-                .bind(id_election)
-                .bind(nf)
-                .execute(&mut *connection)
-                .await?;
-
-            sqlx::query("INSERT INTO cmxs(election, hash) VALUES (?, ?)")
-                .bind(id_election)
-                .bind(cmx)
-                .execute(&mut *connection)
-                .await?;
-            if let Some(id) = nfs_cache.get(&as_byte256(nf)) {
-                mark_spent(connection, *id, block.height as u32).await?;
+            if let Some(id) = nfs_cache.get(&as_byte256(&a.nullifier)) {
+                mark_spent(connection, *id, height).await?;
+                n_spent += 1;
             }
-            position += 1;
+            nfs.push(a.nullifier);
+            cmxs.push(a.cmx);
         }
     }
-    store_prop(connection, "height", &block.height.to_string()).await?;
+    let counts = BlockCounts {
+        actions: cmxs.len(),
+        notes: n_notes,
+        spent_notes: n_spent,
+    };
+    store_nfs(connection, id_election, height, &nfs).await?;
+    store_cmxs(connection, id_election, height, &cmxs).await?;
+    store_block(connection, id_election, height, &block.hash).await?;
+    store_sync_height(connection, id_election, height).await?;
 
-    Ok(position)
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use orchard::{
+        keys::SpendingKey,
+        note::{ExtractedNoteCommitment, Nullifier, RandomSeed, Rho},
+        note_encryption::OrchardDomain,
+        value::NoteValue,
+    };
+    use sqlx::Connection as _;
+    use zcash_note_encryption::{Domain as _, NoteEncryption};
+
+    use super::*;
+    use crate::{
+        db::{create_schema, store_election},
+        rpc::{CompactOrchardAction, CompactTx},
+        source::MemoryBlockSource,
+    };
+
+    const START: u32 = 100;
+    // Heights of the note received by the test account and of its spend
+    const NOTE_HEIGHT: u32 = START + 3;
+    const SPEND_HEIGHT: u32 = START + 15;
+
+    // A canonical field element, different for every tag, height and index
+    fn hash(tag: u8, height: u32, index: u32) -> Vec<u8> {
+        let mut hash = vec![0u8; 32];
+        hash[0] = tag;
+        hash[1..5].copy_from_slice(&height.to_le_bytes());
+        hash[5..9].copy_from_slice(&index.to_le_bytes());
+        hash
+    }
+
+    fn keys() -> (FullViewingKey, Vec<(u32, FullViewingKey)>) {
+        let sk = SpendingKey::from_bytes([1u8; 32]).unwrap();
+        let fvk = FullViewingKey::from(&sk);
+        (fvk.clone(), vec![(0, fvk)])
+    }
+
+    fn output(fvk: &FullViewingKey, nf: Vec<u8>) -> (CompactOrchardAction, Note) {
+        let rho = Rho::from_nf_old(Nullifier::from_bytes(&as_byte256(&nf)).unwrap());
+        let rseed = RandomSeed::from_bytes([7u8; 32], &rho).unwrap();
+        let recipient = fvk.address_at(0u32, Scope::External);
+        let note = Note::from_parts(recipient, NoteValue::from_raw(1000), rho, rseed).unwrap();
+        let encryption = NoteEncryption::<OrchardDomain>::new(None, note, [0u8; 512]);
+        let ciphertext = encryption.encrypt_note_plaintext();
+        let action = CompactOrchardAction {
+            nullifier: nf,
+            cmx: ExtractedNoteCommitment::from(note.commitment())
+                .to_bytes()
+                .to_vec(),
+            ephemeral_key: OrchardDomain::epk_bytes(encryption.epk()).0.to_vec(),
+            ciphertext: ciphertext.as_ref()[..52].to_vec(),
+        };
+        (action, note)
+    }
+
+    /// Blocks after the election start up to `end`. The blocks after
+    /// `fork_height` belong to the chain `fork`
+    fn chain(fvk: &FullViewingKey, fork: u8, fork_height: u32, end: u32) -> Vec<CompactBlock> {
+        let tag = |height: u32| if height > fork_height { fork } else { 0 };
+        let (received, note) = output(fvk, hash(0x40, NOTE_HEIGHT, 0));
+        (START + 1..=end)
+            .map(|height| {
+                let t = tag(height);
+                let mut actions = (0..2)
+                    .map(|i| CompactOrchardAction {
+                        nullifier: hash(0x10 | t, height, i),
+                        cmx: hash(0x20 | t, height, i),
+                        ephemeral_key: vec![0xEE; 32],
+                        ciphertext: vec![0u8; 52],
+                    })
+                    .collect::<Vec<_>>();
+                if height == NOTE_HEIGHT {
+                    actions.push(received.clone());
+                }
+                if height == SPEND_HEIGHT {
+                    actions[0].nullifier = note.nullifier(fvk).to_bytes().to_vec();
+                }
+                CompactBlock {
+                    height: height as u64,
+                    hash: hash(0x30 | t, height, 0),
+                    prev_hash: hash(0x30 | tag(height - 1), height - 1, 0),
+                    vtx: vec![CompactTx {
+                        hash: hash(0x50 | t, height, 0),
+                        actions,
+                        ..CompactTx::default()
+                    }],
+                    ..CompactBlock::default()
+                }
+            })
+            .collect()
+    }
+
+    fn election(end: u32) -> Election {
+        Election {
+            start_height: START,
+            end_height: end,
+            ..Election::default()
+        }
+    }
+
+    async fn database(election: &Election) -> (SqliteConnection, u32) {
+        let mut connection = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        create_schema(&mut connection).await.unwrap();
+        let id_election = store_election(&mut connection, election).await.unwrap();
+        (connection, id_election)
+    }
+
+    async fn sync(
+        connection: &mut SqliteConnection,
+        id_election: u32,
+        election: &Election,
+        fvks: &[(u32, FullViewingKey)],
+        blocks: &[CompactBlock],
+    ) -> Result<u32> {
+        let mut source = MemoryBlockSource::new(blocks.to_vec());
+        let options = SyncOptions::new(|_| ());
+        download_reference_data(
+            connection,
+            id_election,
+            election,
+            fvks,
+            &mut source,
+            &options,
+        )
+        .await
+    }
+
+    #[derive(PartialEq, Debug)]
+    struct Snapshot {
+        blocks: Vec<(u32, Vec<u8>)>,
+        nfs: Vec<(u32, Vec<u8>)>,
+        cmxs: Vec<(u32, Vec<u8>)>,
+        notes: Vec<(u32, u32, i64, Vec<u8>, Option<u32>)>,
+    }
+
+    async fn hashes(
+        connection: &mut SqliteConnection,
+        id_election: u32,
+        table: &str,
+        order: &str,
+    ) -> Vec<(u32, Vec<u8>)> {
+        sqlx::query_as(&format!(
+            "SELECT height, hash FROM {table} WHERE election = ? ORDER BY {order}"
+        ))
+        .bind(id_election)
+        .fetch_all(connection)
+        .await
+        .unwrap()
+    }
+
+    async fn snapshot(connection: &mut SqliteConnection, id_election: u32) -> Snapshot {
+        let blocks = hashes(connection, id_election, "blocks", "height").await;
+        let nfs = hashes(connection, id_election, "nfs", "id_nf").await;
+        let cmxs = hashes(connection, id_election, "cmxs", "id_cmx").await;
+        let notes = sqlx::query_as(
+            "SELECT position, height, value, nf, spent FROM notes
+            WHERE election = ? ORDER BY position",
+        )
+        .bind(id_election)
+        .fetch_all(&mut *connection)
+        .await
+        .unwrap();
+        Snapshot {
+            blocks,
+            nfs,
+            cmxs,
+            notes,
+        }
+    }
+
+    #[tokio::test]
+    async fn reorg_rolls_back_to_the_fork() {
+        let end = START + 20;
+        let election = election(end);
+        let (fvk, fvks) = keys();
+        let a = chain(&fvk, 1, START + 5, end);
+        let b = chain(&fvk, 2, START + 5, end);
+
+        let (mut connection, id_election) = database(&election).await;
+        let r = sync(&mut connection, id_election, &election, &fvks, &a[..10]).await;
+        assert!(matches!(r, Err(VoteError::MissingBlocks(h)) if h == START + 10));
+        let r = sync(&mut connection, id_election, &election, &fvks, &b).await;
+        assert_eq!(r.unwrap(), end);
+
+        let (mut expected, id_expected) = database(&election).await;
+        sync(&mut expected, id_expected, &election, &fvks, &b)
+            .await
+            .unwrap();
+        let synced = snapshot(&mut connection, id_election).await;
+        assert_eq!(synced, snapshot(&mut expected, id_expected).await);
+        assert_eq!(synced.notes.len(), 1);
+        assert_eq!(synced.notes[0].4, Some(SPEND_HEIGHT));
+    }
+
+    #[tokio::test]
+    async fn inconsistent_chain_is_rejected() {
+        let end = START + 20;
+        let election = election(end);
+        let (fvk, fvks) = keys();
+        let mut blocks = chain(&fvk, 0, end, end);
+
+        let (mut connection, id_election) = database(&election).await;
+        let r = sync(
+            &mut connection,
+            id_election,
+            &election,
+            &fvks,
+            &blocks[..10],
+        )
+        .await;
+        assert!(matches!(r, Err(VoteError::MissingBlocks(_))));
+        // the block at START + 10 is unchanged, the next one does not follow it
+        blocks[10].prev_hash = vec![0xFF; 32];
+        let r = sync(&mut connection, id_election, &election, &fvks, &blocks).await;
+        assert!(matches!(r, Err(VoteError::InconsistentChain(h)) if h == START + 11));
+        assert_eq!(
+            load_sync_height(&mut connection, id_election)
+                .await
+                .unwrap(),
+            Some(START + 10)
+        );
+    }
+
+    #[tokio::test]
+    async fn resumed_sync_matches_uninterrupted_sync() {
+        // several chunks, so that the cancellation lands in the middle
+        let end = START + 2 * BLOCKS_PER_CHUNK as u32 + 50;
+        let election = election(end);
+        let (fvk, fvks) = keys();
+        let blocks = chain(&fvk, 0, end, end);
+
+        let (mut connection, id_election) = database(&election).await;
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let options = SyncOptions {
+            retry_policy: RetryPolicy::default(),
+            cancel,
+            progress: move |_: SyncEvent| token.cancel(),
+        };
+        let mut source = MemoryBlockSource::new(blocks.clone());
+        let r = download_reference_data(
+            &mut connection,
+            id_election,
+            &election,
+            &fvks,
+            &mut source,
+            &options,
+        )
+        .await;
+        let Err(VoteError::Cancelled(height)) = r else {
+            panic!("sync was not cancelled");
+        };
+        assert!(height < end);
+        assert_eq!(
+            load_sync_height(&mut connection, id_election)
+                .await
+                .unwrap(),
+            Some(height)
+        );
+        let r = sync(&mut connection, id_election, &election, &fvks, &blocks).await;
+        assert_eq!(r.unwrap(), end);
+
+        let (mut expected, id_expected) = database(&election).await;
+        sync(&mut expected, id_expected, &election, &fvks, &blocks)
+            .await
+            .unwrap();
+        let synced = snapshot(&mut connection, id_election).await;
+        assert_eq!(synced, snapshot(&mut expected, id_expected).await);
+        assert_eq!(synced.notes.len(), 1);
+    }
 }
@@ -1,4 +1,8 @@
+use std::collections::HashSet;
+
 use orchard::{
+    note::ExtractedNoteCommitment,
+    tree::{MerkleHashOrchard, MerklePath},
     vote::{Circuit, Frontier, OrchardHash, ProvingKey, VerifyingKey},
     Address,
 };
@@ -7,7 +11,12 @@ use pasta_curves::group::ff::PrimeField as _;
 use prost::Message;
 use serde::{Deserialize, Serialize};
 
-use crate::{address::VoteAddress, errors::VoteError, pb::{self, Candidate}};
+use crate::{
+    address::VoteAddress,
+    errors::{ElectionError, VoteError},
+    pb::{self, Candidate},
+    DEPTH,
+};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct CandidateChoice {
@@ -45,6 +54,60 @@ impl Election {
         Ok(election)
     }
 
+    /// Check the definition before it is published.
+    /// Returns every problem found
+    pub fn validate(&self) -> Result<(), Vec<ElectionError>> {
+        let mut errors = vec![];
+        if self.start_height >= self.end_height {
+            errors.push(ElectionError::HeightRange {
+                start: self.start_height,
+                end: self.end_height,
+            });
+        }
+        if self.candidates.len() < 2 {
+            errors.push(ElectionError::NotEnoughCandidates(self.candidates.len()));
+        }
+        let mut addresses = HashSet::new();
+        let mut choices = HashSet::new();
+        for (index, c) in self.candidates.iter().enumerate() {
+            match VoteAddress::decode(&c.address) {
+                // bech32 is case insensitive, compare the receivers
+                Ok(address) => {
+                    if !addresses.insert(address.0.to_raw_address_bytes()) {
+                        errors.push(ElectionError::DuplicateAddress(c.address.clone()));
+                    }
+                }
+                Err(_) => errors.push(ElectionError::InvalidAddress {
+                    index,
+                    address: c.address.clone(),
+                }),
+            }
+            if !choices.insert(&c.choice) {
+                errors.push(ElectionError::DuplicateChoice(c.choice.clone()));
+            }
+        }
+
+        // anchors are all zeros until the election is finalized
+        let cmx_present = self.cmx.0 != [0u8; 32];
+        if cmx_present && Fp::from_repr(self.cmx.0).is_none().into() {
+            errors.push(ElectionError::InvalidCmx);
+        }
+        if self.nf.0 != [0u8; 32] && Fp::from_repr(self.nf.0).is_none().into() {
+            errors.push(ElectionError::InvalidNf);
+        }
+        if let Some(frontier) = &self.cmx_frontier {
+            if let Err(e) = check_frontier(frontier, &self.cmx) {
+                errors.push(ElectionError::InvalidFrontier(e));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     pub fn id(&self) -> String {
         hex::encode(self.domain().to_repr())
     }
@@ -69,6 +132,31 @@ impl Election {
     }
 }
 
+// The path of the frontier leaf must lead to the cmx root
+fn check_frontier(frontier: &Frontier, cmx: &OrchardHash) -> Result<(), String> {
+    let path = frontier
+        .ommers
+        .iter()
+        .map(|o| {
+            MerkleHashOrchard::from_bytes(&o.0)
+                .into_option()
+                .ok_or("invalid ommer".to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let path: [MerkleHashOrchard; DEPTH] = path
+        .try_into()
+        .map_err(|p: Vec<_>| format!("{} ommers instead of {DEPTH}", p.len()))?;
+    let leaf = ExtractedNoteCommitment::from_bytes(&frontier.leaf.0)
+        .into_option()
+        .ok_or("invalid leaf".to_string())?;
+    let position = frontier.position as u32;
+    let root = MerklePath::from_parts(position, path).root(leaf).to_bytes();
+    if root != cmx.0 {
+        return Err(format!("root is {}", hex::encode(root)));
+    }
+    Ok(())
+}
+
 lazy_static::lazy_static! {
     pub static ref BALLOT_PK: ProvingKey<Circuit> = ProvingKey::build();
     pub static ref BALLOT_VK: VerifyingKey<Circuit> = VerifyingKey::build();
@@ -1,7 +1,7 @@
 use halo2_proofs::plonk::Error as PlonkError;
 use http::uri::InvalidUri;
 use thiserror::Error;
-use tonic::{transport::Error as TonicTransportError, Status};
+use tonic::{transport::Error as TonicTransportError, Code, Status};
 
 #[derive(Error, Debug)]
 pub enum VoteError {
@@ -17,6 +17,10 @@ pub enum VoteError {
     TonicError(#[from] Status),
     #[error(transparent)]
     OrchardVoteError(#[from] orchard::vote::VoteError),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
 
     #[error("Note at position {0} is out of range")]
     OutOfRange(usize),
@@ -24,9 +28,93 @@ pub enum VoteError {
     DoubleNullifier(String),
     #[error("Invalid JSON: {0}")]
     InvalidJson(String),
+    #[error("Ballot {0} already accepted")]
+    DuplicateBallot(String),
     #[error("Invalid Ballot: {0}")]
-    InvalidBallot(String),
+    InvalidBallot(#[from] InvalidBallot),
+    #[error("Commitment tree does not match the chain: {0}")]
+    CmxTreeMismatch(String),
+    #[error("Servers do not agree on block {0}")]
+    NoQuorum(u32),
+    #[error("Server chain is inconsistent at block {0}")]
+    InconsistentChain(u32),
+    #[error("Block source has no blocks after {0}")]
+    MissingBlocks(u32),
+    #[error("Sync cancelled at {0}")]
+    Cancelled(u32),
+    #[error("Not enough voting power: {available} available, {required} required")]
+    NotEnoughVotes { available: u64, required: u64 },
+    #[error("{0} is not a candidate of the election")]
+    UnknownCandidate(String),
+    #[error("Vote amount must not be zero")]
+    ZeroAmount,
+    #[error("Invalid election anchors: {0}")]
+    InvalidAnchors(String),
+    #[error("Invalid transcript: {0}")]
+    InvalidTranscript(String),
+    #[error("Election is synced to {height:?}, it ends at {end_height}")]
+    NotSynced { height: Option<u32>, end_height: u32 },
 
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
+
+/// Reason a ballot was rejected by [`crate::ballot::verify_ballot`]
+#[derive(Error, Clone, PartialEq, Eq, Debug)]
+pub enum InvalidBallot {
+    #[error("ballot is for another election")]
+    Domain,
+    #[error("note commitment anchor does not match the election")]
+    CmxAnchor,
+    #[error("nullifier anchor does not match the election")]
+    NfAnchor,
+    #[error("signature required")]
+    MissingSignature,
+    #[error("invalid signature: {0}")]
+    Signature(String),
+    #[error("proof does not verify: {0}")]
+    Proof(String),
+}
+
+/// Problem found by [`crate::election::Election::validate`]
+#[derive(Error, Clone, PartialEq, Eq, Debug)]
+pub enum ElectionError {
+    #[error("start height {start} is not before end height {end}")]
+    HeightRange { start: u32, end: u32 },
+    #[error("{0} candidates, at least 2 are needed")]
+    NotEnoughCandidates(usize),
+    #[error("candidate {index} has an invalid address {address}")]
+    InvalidAddress { index: usize, address: String },
+    #[error("address {0} is used by several candidates")]
+    DuplicateAddress(String),
+    #[error("choice {0} is used by several candidates")]
+    DuplicateChoice(String),
+    #[error("cmx is not a field element")]
+    InvalidCmx,
+    #[error("nf is not a field element")]
+    InvalidNf,
+    #[error("cmx frontier does not match cmx: {0}")]
+    InvalidFrontier(String),
+}
+
+impl VoteError {
+    /// Network errors that may go away if the request is retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            VoteError::TonicTransportError(_) => true,
+            // the server may be switching to another chain
+            VoteError::InconsistentChain(_) => true,
+            VoteError::TonicError(status) => matches!(
+                status.code(),
+                Code::Unavailable
+                    | Code::DeadlineExceeded
+                    | Code::ResourceExhausted
+                    | Code::Aborted
+                    | Code::Cancelled
+                    | Code::Internal
+                    | Code::Unknown
+            ),
+            _ => false,
+        }
+    }
+}
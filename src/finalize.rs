@@ -0,0 +1,42 @@
+use sqlx::SqliteConnection;
+
+use crate::{
+    db::{find_election, load_election_source, load_sync_height, store_election},
+    election::Election,
+    errors::VoteError,
+    source::SyncOrigin,
+    trees::{compute_cmx_root, compute_nf_root},
+    Result,
+};
+
+/// Fill in the anchors and the commitment frontier of `election`
+/// from its synced data, and save it. Returns the election with
+/// the server or archive the data was synced from, as recorded
+/// by the sync. The sync must have reached the end of the election
+pub async fn finalize_election(
+    connection: &mut SqliteConnection,
+    election: &Election,
+) -> Result<(Election, SyncOrigin)> {
+    let id_election = find_election(connection, &election.id())
+        .await?
+        .ok_or(anyhow::anyhow!("Unknown election {}", election.id()))?;
+    let height = load_sync_height(connection, id_election).await?;
+    if height.is_none_or(|h| h < election.end_height) {
+        return Err(VoteError::NotSynced {
+            height,
+            end_height: election.end_height,
+        });
+    }
+
+    let (cmx, cmx_frontier) = compute_cmx_root(connection, id_election).await?;
+    let nf = compute_nf_root(connection, id_election).await?;
+    let election = Election {
+        cmx,
+        nf,
+        cmx_frontier,
+        ..election.clone()
+    };
+    store_election(connection, &election).await?;
+    let origin = load_election_source(connection, id_election).await?;
+    Ok((election, origin))
+}
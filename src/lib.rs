@@ -13,10 +13,17 @@ pub type Result<T> = std::result::Result<T, VoteError>;
 
 pub mod pb;
 pub mod address;
+pub mod archive;
+pub mod ballot;
 pub mod db;
 pub mod decrypt;
 pub mod download;
 pub mod election;
+pub mod finalize;
+pub mod lwd;
+pub mod quorum;
+pub mod source;
+pub mod tally;
 pub mod trees;
 pub mod validate;
 
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use tonic::{
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint},
+    Request,
+};
+
+use crate::{rpc::compact_tx_streamer_client::CompactTxStreamerClient, Result};
+
+const BUNDLED_CA: &[u8] = include_bytes!("ca.pem");
+
+/// Trust anchors for the TLS connections to lightwalletd
+#[derive(Clone, Debug)]
+pub enum TlsRoots {
+    /// The CA certificate shipped with the crate
+    Bundled,
+    /// A PEM encoded CA bundle
+    Custom(Vec<u8>),
+    /// The Mozilla root certificates
+    WebPki,
+}
+
+/// Builder for the connections to a lightwalletd server.
+/// TLS is only used for `https` urls
+#[derive(Clone, Debug)]
+pub struct LwdEndpoint {
+    url: String,
+    tls: TlsRoots,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    keep_alive: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+impl LwdEndpoint {
+    pub fn new(url: &str) -> Self {
+        LwdEndpoint {
+            url: url.to_string(),
+            tls: TlsRoots::WebPki,
+            connect_timeout: None,
+            timeout: None,
+            keep_alive: None,
+            request_timeout: None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn tls(mut self, roots: TlsRoots) -> Self {
+        self.tls = roots;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout applied by the channel to every call, streams included
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Interval of the HTTP/2 keep-alive pings
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
+    /// Deadline sent with the unary requests made by [`LwdEndpoint::request`]
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub async fn connect(&self) -> Result<CompactTxStreamerClient<Channel>> {
+        let mut ep = Endpoint::from_shared(self.url.clone())?;
+        if ep.uri().scheme_str() == Some("https") {
+            let tls = ClientTlsConfig::new();
+            let tls = match &self.tls {
+                TlsRoots::Bundled => tls.ca_certificate(Certificate::from_pem(BUNDLED_CA)),
+                TlsRoots::Custom(pem) => tls.ca_certificate(Certificate::from_pem(pem)),
+                TlsRoots::WebPki => tls.with_webpki_roots(),
+            };
+            ep = ep.tls_config(tls)?;
+        }
+        if let Some(timeout) = self.connect_timeout {
+            ep = ep.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            ep = ep.timeout(timeout);
+        }
+        if let Some(interval) = self.keep_alive {
+            ep = ep
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(true)
+                .tcp_keepalive(Some(interval));
+        }
+        let client = CompactTxStreamerClient::connect(ep).await?;
+        Ok(client)
+    }
+
+    /// Wrap a message in a request, with the request deadline if any
+    pub fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(timeout) = self.request_timeout {
+            request.set_timeout(timeout);
+        }
+        request
+    }
+}
@@ -0,0 +1,332 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use blake2b_simd::Params;
+use futures::{future::join_all, StreamExt as _};
+use orchard::keys::FullViewingKey;
+use sqlx::SqliteConnection;
+
+use crate::{
+    as_byte256,
+    download::{download_reference_data, SyncEvent, SyncOptions},
+    election::Election,
+    errors::VoteError,
+    rpc::CompactBlock,
+    source::{BlockSource, BlockStream, SyncOrigin},
+    Hash, Result,
+};
+
+#[derive(Clone, Debug)]
+pub enum DivergenceKind {
+    /// The server has a different block at this height
+    BlockHash,
+    /// Same block hash but different actions
+    Actions,
+    /// The server did not return the block
+    Missing,
+    /// The server returned a block past the end of the range
+    Extra,
+    Error(String),
+}
+
+/// A server that disagreed with the quorum
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub server: String,
+    pub height: u32,
+    pub kind: DivergenceKind,
+}
+
+/// Block source that streams the same range from several servers
+/// and only returns the blocks that at least `quorum` of them agree on.
+/// Servers are compared by block hash and by a digest of the Orchard
+/// actions of the block. The servers that disagree are recorded
+/// and then ignored for the rest of the stream
+pub struct QuorumBlockSource<S> {
+    sources: Vec<(String, S)>,
+    quorum: usize,
+    divergences: Arc<Mutex<Vec<Divergence>>>,
+}
+
+impl<S: BlockSource> QuorumBlockSource<S> {
+    /// `sources` are pairs of server name and block source.
+    /// `quorum` must be between 1 and the number of sources
+    pub fn new(sources: Vec<(String, S)>, quorum: usize) -> Result<Self> {
+        if quorum == 0 || quorum > sources.len() {
+            return Err(anyhow::anyhow!(
+                "Quorum of {quorum} with {} servers",
+                sources.len()
+            )
+            .into());
+        }
+        Ok(QuorumBlockSource {
+            sources,
+            quorum,
+            divergences: Arc::new(Mutex::new(vec![])),
+        })
+    }
+
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.divergences.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl<S: BlockSource> BlockSource for QuorumBlockSource<S> {
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream> {
+        let mut servers = vec![];
+        let mut streams = vec![];
+        for (server, source) in self.sources.iter_mut() {
+            servers.push(server.clone());
+            // a server that cannot be reached does not stop the others
+            let stream = match source.get_block_range(start, end).await {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    let kind = DivergenceKind::Error(e.to_string());
+                    log::warn!("Server {server} diverged at {start}: {kind:?}");
+                    self.divergences.lock().unwrap().push(Divergence {
+                        server: server.clone(),
+                        height: start,
+                        kind,
+                    });
+                    None
+                }
+            };
+            streams.push(stream);
+        }
+        let stream = QuorumStream {
+            servers,
+            streams,
+            quorum: self.quorum,
+            height: start,
+            divergences: self.divergences.clone(),
+        };
+        let blocks = futures::stream::unfold(stream, |mut stream| async move {
+            match stream.next_block().await {
+                Ok(Some(block)) => Some((Ok(block), stream)),
+                Ok(None) => None,
+                Err(e) => {
+                    // end the stream after an error
+                    stream.streams.clear();
+                    Some((Err(e), stream))
+                }
+            }
+        });
+        Ok(blocks.boxed())
+    }
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock> {
+        let mut servers = vec![];
+        let mut replies = vec![];
+        for (server, source) in self.sources.iter_mut() {
+            servers.push(server.clone());
+            let reply = match source.get_block(height).await {
+                Ok(block) => Reply::Block(block),
+                Err(e) => Reply::Error(e),
+            };
+            replies.push(Some(reply));
+        }
+        let (block, _) = select_block(&servers, replies, self.quorum, height, &self.divergences)?;
+        Ok(block)
+    }
+
+    fn is_remote(&self) -> bool {
+        self.sources.iter().any(|(_, source)| source.is_remote())
+    }
+
+    fn origin(&self) -> SyncOrigin {
+        let servers = self
+            .sources
+            .iter()
+            .map(|(server, _)| server.as_str())
+            .collect::<Vec<_>>();
+        SyncOrigin {
+            server: Some(format!("{} of {}", self.quorum, servers.join(","))),
+            archive_hash: None,
+        }
+    }
+}
+
+struct QuorumStream {
+    servers: Vec<String>,
+    // None once the server has diverged
+    streams: Vec<Option<BlockStream>>,
+    quorum: usize,
+    height: u32,
+    divergences: Arc<Mutex<Vec<Divergence>>>,
+}
+
+impl QuorumStream {
+    async fn next_block(&mut self) -> Result<Option<CompactBlock>> {
+        let replies = join_all(self.streams.iter_mut().map(|s| async move {
+            let reply = match s.as_mut()?.next().await {
+                Some(Ok(block)) => Reply::Block(block),
+                Some(Err(e)) => Reply::Error(e),
+                None => Reply::Missing,
+            };
+            Some(reply)
+        }))
+        .await;
+        // a missing block is a vote for the end of the range
+        let missing = replies
+            .iter()
+            .flatten()
+            .filter(|r| matches!(r, Reply::Missing))
+            .count();
+        if missing >= self.quorum || missing == replies.iter().flatten().count() {
+            let mut divergences = self.divergences.lock().unwrap();
+            for (i, reply) in replies.iter().enumerate() {
+                if let Some(Reply::Block(_)) = reply {
+                    log::warn!(
+                        "Server {} diverged at {}: {:?}",
+                        self.servers[i],
+                        self.height,
+                        DivergenceKind::Extra
+                    );
+                    divergences.push(Divergence {
+                        server: self.servers[i].clone(),
+                        height: self.height,
+                        kind: DivergenceKind::Extra,
+                    });
+                }
+            }
+            return Ok(None);
+        }
+        let (block, diverged) = select_block(
+            &self.servers,
+            replies,
+            self.quorum,
+            self.height,
+            &self.divergences,
+        )?;
+        for i in diverged {
+            self.streams[i] = None;
+        }
+        self.height += 1;
+        Ok(Some(block))
+    }
+}
+
+enum Reply {
+    Block(CompactBlock),
+    Missing,
+    Error(VoteError),
+}
+
+/// Pick the block most servers agree on and record the others.
+/// A `None` reply is from a server that is no longer queried.
+/// Returns the block and the indices of the servers that diverged
+fn select_block(
+    servers: &[String],
+    replies: Vec<Option<Reply>>,
+    quorum: usize,
+    height: u32,
+    divergences: &Mutex<Vec<Divergence>>,
+) -> Result<(CompactBlock, Vec<usize>)> {
+    let mut candidates: Vec<(Vec<u8>, Hash, Vec<usize>, CompactBlock)> = vec![];
+    let mut diverged = vec![];
+    let mut divergences = divergences.lock().unwrap();
+    let mut report = |i: usize, kind: DivergenceKind| {
+        log::warn!("Server {} diverged at {height}: {kind:?}", servers[i]);
+        divergences.push(Divergence {
+            server: servers[i].clone(),
+            height,
+            kind,
+        });
+        diverged.push(i);
+    };
+    for (i, reply) in replies.into_iter().enumerate() {
+        match reply {
+            Some(Reply::Block(block)) => {
+                let digest = action_digest(&block);
+                match candidates
+                    .iter_mut()
+                    .find(|(hash, d, _, _)| *hash == block.hash && *d == digest)
+                {
+                    Some((_, _, members, _)) => members.push(i),
+                    None => candidates.push((block.hash.clone(), digest, vec![i], block)),
+                }
+            }
+            Some(Reply::Missing) => report(i, DivergenceKind::Missing),
+            Some(Reply::Error(e)) => report(i, DivergenceKind::Error(e.to_string())),
+            None => {}
+        }
+    }
+
+    candidates.sort_by_key(|(_, _, members, _)| std::cmp::Reverse(members.len()));
+    let mut candidates = candidates.into_iter();
+    let Some((hash, _, members, block)) = candidates.next() else {
+        return Err(VoteError::NoQuorum(height));
+    };
+    if members.len() < quorum {
+        log::warn!(
+            "Only {} of {} servers agree on block {height}",
+            members.len(),
+            servers.len()
+        );
+        return Err(VoteError::NoQuorum(height));
+    }
+    let candidates = candidates.collect::<Vec<_>>();
+    // with a quorum of half the servers or less, two blocks can reach it
+    if candidates.first().is_some_and(|(_, _, m, _)| m.len() >= quorum) {
+        log::warn!("Conflicting blocks reach the quorum at {height}");
+        return Err(VoteError::NoQuorum(height));
+    }
+    for (h, _, members, _) in candidates {
+        let kind = if h != hash {
+            DivergenceKind::BlockHash
+        } else {
+            DivergenceKind::Actions
+        };
+        for i in members {
+            report(i, kind.clone());
+        }
+    }
+    Ok((block, diverged))
+}
+
+fn action_digest(block: &CompactBlock) -> Hash {
+    let mut state = Params::new()
+        .hash_length(32)
+        .personal(b"ZcashVote_Digest")
+        .to_state();
+    for tx in block.vtx.iter() {
+        for a in tx.actions.iter() {
+            state.update(&a.nullifier);
+            state.update(&a.cmx);
+            state.update(&a.ephemeral_key);
+            state.update(&a.ciphertext);
+        }
+    }
+    as_byte256(state.finalize().as_bytes())
+}
+
+/// Download the election range from several servers and only keep
+/// the blocks that `quorum` of them agree on. Returns the end height
+/// and the list of servers that diverged
+pub async fn download_reference_data_quorum<S: BlockSource>(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    election: &Election,
+    fvks: &[(u32, FullViewingKey)],
+    sources: Vec<(String, S)>,
+    quorum: usize,
+    options: &SyncOptions<impl Fn(SyncEvent) + Send + 'static>,
+) -> Result<(u32, Vec<Divergence>)> {
+    let mut source = QuorumBlockSource::new(sources, quorum)?;
+    let r = download_reference_data(
+        connection,
+        id_election,
+        election,
+        fvks,
+        &mut source,
+        options,
+    )
+    .await;
+    let divergences = source.divergences();
+    for d in divergences.iter() {
+        log::info!("{d:?}");
+    }
+    let height = r?;
+    Ok((height, divergences))
+}
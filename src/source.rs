@@ -0,0 +1,341 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt as _};
+use prost::Message as _;
+use tokio::sync::mpsc;
+use tonic::{transport::Channel, Request};
+
+use crate::{
+    errors::VoteError,
+    lwd::LwdEndpoint,
+    rpc::{
+        compact_tx_streamer_client::CompactTxStreamerClient, BlockId, BlockRange, CompactBlock,
+        TreeState,
+    },
+    Hash, Result,
+};
+
+pub type BlockStream = BoxStream<'static, Result<CompactBlock>>;
+
+/// Where the sync gets its compact blocks from
+#[async_trait]
+pub trait BlockSource: Send {
+    /// Stream the blocks from `start` to `end`, both inclusive, in order
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream>;
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock>;
+
+    /// Whether the blocks come over the network. A stream that ends
+    /// early is only worth retrying for a remote source
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    /// Where the blocks come from, recorded with the synced data
+    fn origin(&self) -> SyncOrigin {
+        SyncOrigin::default()
+    }
+}
+
+/// Where the data of an election was synced from
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct SyncOrigin {
+    /// Url of the server, or description of the servers or files
+    pub server: Option<String>,
+    /// Hash of the block archive
+    pub archive_hash: Option<Hash>,
+}
+
+/// Blocks served by a lightwalletd server
+pub struct LwdBlockSource {
+    endpoint: LwdEndpoint,
+    client: CompactTxStreamerClient<Channel>,
+}
+
+impl LwdBlockSource {
+    pub async fn connect(lwd_url: &str) -> Result<Self> {
+        Self::connect_with(LwdEndpoint::new(lwd_url)).await
+    }
+
+    pub async fn connect_with(endpoint: LwdEndpoint) -> Result<Self> {
+        let client = endpoint.connect().await?;
+        Ok(LwdBlockSource { endpoint, client })
+    }
+
+    pub fn endpoint(&self) -> &LwdEndpoint {
+        &self.endpoint
+    }
+
+    /// Note commitment tree states after the block at `height`
+    pub async fn get_tree_state(&mut self, height: u32) -> Result<TreeState> {
+        let tree_state = self
+            .client
+            .get_tree_state(self.endpoint.request(BlockId {
+                height: height as u64,
+                hash: vec![],
+            }))
+            .await?
+            .into_inner();
+        Ok(tree_state)
+    }
+}
+
+#[async_trait]
+impl BlockSource for LwdBlockSource {
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream> {
+        // no request deadline, the stream lasts as long as the range
+        let blocks = self
+            .client
+            .get_block_range(Request::new(BlockRange {
+                start: Some(BlockId {
+                    height: start as u64,
+                    hash: vec![],
+                }),
+                end: Some(BlockId {
+                    height: end as u64,
+                    hash: vec![],
+                }),
+                spam_filter_threshold: 0,
+            }))
+            .await?
+            .into_inner();
+        Ok(blocks.map(|b| b.map_err(VoteError::from)).boxed())
+    }
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock> {
+        let block = self
+            .client
+            .get_block(self.endpoint.request(BlockId {
+                height: height as u64,
+                hash: vec![],
+            }))
+            .await?
+            .into_inner();
+        Ok(block)
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    fn origin(&self) -> SyncOrigin {
+        SyncOrigin {
+            server: Some(self.endpoint.url().to_string()),
+            archive_hash: None,
+        }
+    }
+}
+
+/// Blocks stored as a sequence of length delimited `CompactBlock`
+/// protobufs, in a single file or in the files of a directory
+/// taken in name order
+pub struct FileBlockSource {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+}
+
+impl FileBlockSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let files = if path.is_dir() {
+            let mut files = std::fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.retain(|p| p.is_file());
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+        Ok(FileBlockSource {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockSource for FileBlockSource {
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream> {
+        let files = self.files.clone();
+        Ok(stream_blocks(move || Ok(read_blocks(files)), start, end))
+    }
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock> {
+        let files = self.files.clone();
+        find_block(move || Ok(read_blocks(files)), height).await
+    }
+
+    fn origin(&self) -> SyncOrigin {
+        SyncOrigin {
+            server: Some(format!("file://{}", self.path.display())),
+            archive_hash: None,
+        }
+    }
+}
+
+// Number of blocks read ahead of the sync
+const BLOCK_CHANNEL_CAPACITY: usize = 1000;
+
+/// Stream the blocks between `start` and `end` from a blocking reader.
+/// `open` runs on the blocking thread pool and returns the blocks
+/// in height order
+pub(crate) fn stream_blocks<F, I>(open: F, start: u32, end: u32) -> BlockStream
+where
+    F: FnOnce() -> Result<I> + Send + 'static,
+    I: Iterator<Item = Result<CompactBlock>>,
+{
+    let (sender, receiver) = mpsc::channel(BLOCK_CHANNEL_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let blocks = match open() {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                let _ = sender.blocking_send(Err(e));
+                return;
+            }
+        };
+        for r in blocks {
+            let r = match r {
+                Ok(block) if (block.height as u32) < start => continue,
+                Ok(block) if (block.height as u32) > end => break,
+                r => r,
+            };
+            let failed = r.is_err();
+            if sender.blocking_send(r).is_err() || failed {
+                break;
+            }
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|b| (b, receiver))
+    })
+    .boxed()
+}
+
+pub(crate) async fn find_block<F, I>(open: F, height: u32) -> Result<CompactBlock>
+where
+    F: FnOnce() -> Result<I> + Send + 'static,
+    I: Iterator<Item = Result<CompactBlock>>,
+{
+    tokio::task::spawn_blocking(move || {
+        for block in open()? {
+            let block = block?;
+            if block.height as u32 == height {
+                return Ok(block);
+            }
+        }
+        Err(anyhow::anyhow!("Block {height} not found").into())
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+}
+
+fn read_blocks(files: Vec<PathBuf>) -> impl Iterator<Item = Result<CompactBlock>> {
+    files.into_iter().flat_map(|path| {
+        let reader = File::open(path).map(|f| BlockReader::new(BufReader::new(f)));
+        let (reader, error) = match reader {
+            Ok(reader) => (Some(reader), None),
+            Err(e) => (None, Some(Err(e.into()))),
+        };
+        error.into_iter().chain(reader.into_iter().flatten())
+    })
+}
+
+// Larger than any compact block, protects from corrupt length prefixes
+const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Iterator over the length delimited blocks of a reader
+pub struct BlockReader<R> {
+    reader: R,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(reader: R) -> Self {
+        BlockReader { reader }
+    }
+
+    fn read_block(&mut self) -> Result<Option<CompactBlock>> {
+        let Some(len) = self.read_len()? else {
+            return Ok(None);
+        };
+        if len > MAX_BLOCK_SIZE {
+            return Err(anyhow::anyhow!("Block of {len} bytes is too large").into());
+        }
+        let mut buffer = vec![0u8; len];
+        self.reader.read_exact(&mut buffer)?;
+        let block = CompactBlock::decode(&*buffer)?;
+        Ok(Some(block))
+    }
+
+    // Read the varint length prefix, None at the end of the input
+    fn read_len(&mut self) -> Result<Option<usize>> {
+        let mut len = 0u64;
+        for i in 0..10 {
+            let mut b = [0u8];
+            match self.reader.read_exact(&mut b) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && i == 0 => return Ok(None),
+                r => r?,
+            }
+            len |= ((b[0] & 0x7F) as u64) << (7 * i);
+            if b[0] & 0x80 == 0 {
+                return Ok(Some(len as usize));
+            }
+        }
+        Err(anyhow::anyhow!("Invalid block length").into())
+    }
+}
+
+impl<R: Read> Iterator for BlockReader<R> {
+    type Item = Result<CompactBlock>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_block().transpose()
+    }
+}
+
+/// Append a block in the format read by [`FileBlockSource`]
+pub fn write_block(writer: &mut impl Write, block: &CompactBlock) -> Result<()> {
+    writer.write_all(&block.encode_length_delimited_to_vec())?;
+    Ok(())
+}
+
+/// Blocks held in memory, sorted by height
+pub struct MemoryBlockSource {
+    blocks: Vec<CompactBlock>,
+}
+
+impl MemoryBlockSource {
+    pub fn new(mut blocks: Vec<CompactBlock>) -> Self {
+        blocks.sort_by_key(|b| b.height);
+        MemoryBlockSource { blocks }
+    }
+}
+
+#[async_trait]
+impl BlockSource for MemoryBlockSource {
+    async fn get_block_range(&mut self, start: u32, end: u32) -> Result<BlockStream> {
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|b| (start..=end).contains(&(b.height as u32)))
+            .cloned()
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(futures::stream::iter(blocks).boxed())
+    }
+
+    async fn get_block(&mut self, height: u32) -> Result<CompactBlock> {
+        let block = self
+            .blocks
+            .iter()
+            .find(|b| b.height as u32 == height)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Block {height} not found"))?;
+        Ok(block)
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashSet;
+
+use futures::StreamExt as _;
+use orchard::{
+    keys::{IncomingViewingKey, PreparedIncomingViewingKey, Scope},
+    vote::Ballot,
+    Address,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+
+use crate::{
+    address::VoteAddress,
+    ballot::verify_ballots,
+    db::{load_cmx_root, stream_ballots},
+    decrypt::{to_fvk, try_decrypt_batch},
+    election::Election,
+    errors::VoteError,
+    rpc::CompactOrchardAction,
+    trees::compute_ballot_root,
+    Hash, Result,
+};
+
+// Size of the compact part of a note ciphertext
+const COMPACT_NOTE_SIZE: usize = 52;
+
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct CandidateTally {
+    pub address: String,
+    pub choice: String,
+    pub votes: u64,
+}
+
+/// A ballot output that no candidate key decrypts, usually
+/// the change of the voter
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct UndecryptedOutput {
+    pub ballot: String,
+    pub index: usize,
+}
+
+/// A stored ballot that failed verification and was not counted
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct RejectedBallot {
+    pub ballot: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct Tally {
+    /// Totals in the order of the election candidates
+    pub candidates: Vec<CandidateTally>,
+    /// Hashes of the ballots counted
+    pub ballots: Vec<String>,
+    pub undecrypted: Vec<UndecryptedOutput>,
+    pub rejected: Vec<RejectedBallot>,
+}
+
+// Number of ballots verified together
+const VERIFY_BATCH_SIZE: usize = 256;
+
+/// Counts the ballots of an election with the incoming viewing keys
+/// of the candidates
+pub struct Tallier {
+    ivks: Vec<PreparedIncomingViewingKey>,
+    addresses: Vec<Address>,
+    tally: Tally,
+}
+
+impl Tallier {
+    /// `ivks` are the keys of the election candidates, in the same order.
+    /// Each key must own the address of its candidate
+    pub fn new(election: &Election, ivks: &[IncomingViewingKey]) -> Result<Self> {
+        if ivks.len() != election.candidates.len() {
+            return Err(anyhow::anyhow!(
+                "{} keys for {} candidates",
+                ivks.len(),
+                election.candidates.len()
+            )
+            .into());
+        }
+        let addresses = election
+            .candidates
+            .iter()
+            .map(|c| Ok(VoteAddress::decode(&c.address)?.0))
+            .collect::<Result<Vec<_>>>()?;
+        // a key that does not own the address would count nothing
+        for (i, (ivk, address)) in ivks.iter().zip(addresses.iter()).enumerate() {
+            if ivk.diversifier_index(address).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Key {i} does not match the address of candidate {}",
+                    election.candidates[i].address
+                )
+                .into());
+            }
+        }
+        let candidates = election
+            .candidates
+            .iter()
+            .map(|c| CandidateTally {
+                address: c.address.clone(),
+                choice: c.choice.clone(),
+                votes: 0,
+            })
+            .collect();
+        Ok(Tallier {
+            ivks: ivks.iter().map(PreparedIncomingViewingKey::new).collect(),
+            addresses,
+            tally: Tally {
+                candidates,
+                ..Tally::default()
+            },
+        })
+    }
+
+    /// `keys` are the seed phrases or UFVKs of the candidates,
+    /// in the same order, as accepted by [`to_fvk`]
+    pub fn from_keys(election: &Election, keys: &[String]) -> Result<Self> {
+        let ivks = keys
+            .iter()
+            .map(|key| Ok(to_fvk(key)?.to_ivk(Scope::External)))
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(election, &ivks)
+    }
+
+    /// Add the outputs of a ballot to the totals. The ballot
+    /// must have been verified beforehand
+    pub fn add_ballot(&mut self, hash: &[u8], ballot: &Ballot) -> Result<()> {
+        let hash = hex::encode(hash);
+        let mut indexes = vec![];
+        let mut actions = vec![];
+        for (index, a) in ballot.data.actions.iter().enumerate() {
+            // a truncated ciphertext cannot be a vote for anyone
+            let Some(ciphertext) = a.enc.get(..COMPACT_NOTE_SIZE) else {
+                self.tally.undecrypted.push(UndecryptedOutput {
+                    ballot: hash.clone(),
+                    index,
+                });
+                continue;
+            };
+            indexes.push(index);
+            actions.push(CompactOrchardAction {
+                nullifier: a.nf.clone(),
+                cmx: a.cmx.clone(),
+                ephemeral_key: a.epk.clone(),
+                ciphertext: ciphertext.to_vec(),
+            });
+        }
+        let notes = try_decrypt_batch(&self.ivks, &actions)?;
+        for (index, note) in indexes.into_iter().zip(notes) {
+            match note {
+                // only the address published for the candidate counts
+                Some((note, i)) if note.recipient() == self.addresses[i] => {
+                    let votes = &mut self.tally.candidates[i].votes;
+                    *votes = votes
+                        .checked_add(note.value().inner())
+                        .ok_or(anyhow::anyhow!("Vote count overflow"))?;
+                }
+                _ => self.tally.undecrypted.push(UndecryptedOutput {
+                    ballot: hash.clone(),
+                    index,
+                }),
+            }
+        }
+        self.tally.ballots.push(hash);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Tally {
+        self.tally
+    }
+}
+
+/// Count all the ballots stored for the election. The ballots are
+/// verified first, the ones that fail are reported and not counted
+pub async fn tally_election(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    election: &Election,
+    ivks: &[IncomingViewingKey],
+) -> Result<Tally> {
+    let mut tallier = Tallier::new(election, ivks)?;
+    let mut ballots = stream_ballots(connection, id_election).chunks(VERIFY_BATCH_SIZE);
+    while let Some(chunk) = ballots.next().await {
+        let mut hashes = vec![];
+        let mut batch = vec![];
+        for ballot in chunk {
+            let ballot = ballot?;
+            let data: Ballot = serde_json::from_slice(&ballot.data)
+                .map_err(|e| VoteError::InvalidJson(e.to_string()))?;
+            hashes.push(ballot.hash);
+            batch.push(data);
+        }
+        let e = election.clone();
+        let (batch, verdicts) = tokio::task::spawn_blocking(move || {
+            let verdicts = verify_ballots(&e, &batch);
+            (batch, verdicts)
+        })
+        .await
+        .map_err(anyhow::Error::from)?;
+        for ((hash, ballot), verdict) in hashes.iter().zip(batch.iter()).zip(verdicts) {
+            match verdict {
+                Ok(()) => tallier.add_ballot(hash, ballot)?,
+                Err(e) => tallier.tally.rejected.push(RejectedBallot {
+                    ballot: hex::encode(hash),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+    Ok(tallier.finish())
+}
+
+/// Everything needed to recount an election without the organizer:
+/// the election, its accepted ballots in order, the final root of
+/// the ballot tree and the revealed incoming viewing keys of the candidates
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TallyTranscript {
+    /// Election JSON as published
+    pub election: String,
+    pub id: String,
+    pub ballots: Vec<Ballot>,
+    /// Hex encoded root of the ballot commitment tree, as stored by the
+    /// organizer. Informative only, [`verify_transcript`] checks the
+    /// ballots against a root obtained elsewhere
+    pub ballot_root: String,
+    /// Hex encoded incoming viewing keys, in the order of the candidates
+    pub ivks: Vec<String>,
+}
+
+pub async fn export_transcript(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    election: &Election,
+    ivks: &[IncomingViewingKey],
+) -> Result<TallyTranscript> {
+    let mut ballots = vec![];
+    let mut records = stream_ballots(connection, id_election);
+    while let Some(ballot) = records.next().await {
+        let ballot: Ballot = serde_json::from_slice(&ballot?.data)
+            .map_err(|e| VoteError::InvalidJson(e.to_string()))?;
+        ballots.push(ballot);
+    }
+    drop(records);
+    let ballot_root = match load_cmx_root(connection, id_election, u32::MAX).await? {
+        Some(root) => root,
+        None => compute_ballot_root(&[])?.0.to_vec(),
+    };
+    Ok(TallyTranscript {
+        election: serde_json::to_string(election).map_err(anyhow::Error::from)?,
+        id: election.id(),
+        ballots,
+        ballot_root: hex::encode(ballot_root),
+        ivks: ivks.iter().map(|ivk| hex::encode(ivk.to_bytes())).collect(),
+    })
+}
+
+/// Recount an election from its transcript. Fails if a ballot does not
+/// verify, if two ballots share a nullifier or if the ballots do not
+/// make up the ballot tree. `ballot_root` must come from a source
+/// independent of the transcript, such as the root published while
+/// the election was running
+pub fn verify_transcript(transcript: &TallyTranscript, ballot_root: &Hash) -> Result<Tally> {
+    let election = Election::from_json(&transcript.election)?;
+    if election.id() != transcript.id {
+        return Err(VoteError::InvalidTranscript(format!(
+            "election id is {} not {}",
+            election.id(),
+            transcript.id
+        )));
+    }
+
+    for (i, verdict) in verify_ballots(&election, &transcript.ballots)
+        .into_iter()
+        .enumerate()
+    {
+        if let Err(e) = verdict {
+            return Err(VoteError::InvalidTranscript(format!("ballot {i}: {e}")));
+        }
+    }
+    let mut nfs = HashSet::new();
+    let mut cmxs = vec![];
+    for ballot in transcript.ballots.iter() {
+        for action in ballot.data.actions.iter() {
+            if !nfs.insert(action.nf.clone()) {
+                return Err(VoteError::DoubleNullifier(hex::encode(&action.nf)));
+            }
+            cmxs.push(action.cmx.clone());
+        }
+    }
+    // a missing or extra ballot changes the root
+    let root = compute_ballot_root(&cmxs)?.0;
+    if &root != ballot_root {
+        return Err(VoteError::InvalidTranscript(format!(
+            "ballot root is {} not {}",
+            hex::encode(root),
+            hex::encode(ballot_root)
+        )));
+    }
+
+    let ivks = transcript
+        .ivks
+        .iter()
+        .map(|ivk| {
+            let ivk = hex::decode(ivk).map_err(anyhow::Error::from)?;
+            let ivk: [u8; 64] = ivk
+                .try_into()
+                .map_err(|_| VoteError::InvalidTranscript("invalid ivk".to_string()))?;
+            IncomingViewingKey::from_bytes(&ivk)
+                .into_option()
+                .ok_or(VoteError::InvalidTranscript("invalid ivk".to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut tallier = Tallier::new(&election, &ivks)?;
+    for ballot in transcript.ballots.iter() {
+        let hash = ballot.data.sighash()?;
+        tallier.add_ballot(&hash, ballot)?;
+    }
+    Ok(tallier.finish())
+}
@@ -1,10 +1,33 @@
-use anyhow::Result;
-use orchard::vote::{calculate_merkle_paths, Frontier, OrchardHash};
+use anyhow::{anyhow, Result};
+use incrementalmerkletree::{
+    frontier::{CommitmentTree, NonEmptyFrontier},
+    Hashable as _, Level, Position,
+};
+use orchard::{
+    keys::FullViewingKey,
+    note::Nullifier,
+    tree::{MerkleHashOrchard, MerklePath},
+    vote::{calculate_merkle_paths, Frontier, OrchardHash},
+};
 use pasta_curves::{group::ff::PrimeField as _, Fp};
 use sqlx::{sqlite::SqliteRow, Row, SqliteConnection};
+use zcash_primitives::merkle_tree::read_commitment_tree;
 
-pub async fn list_nf_ranges(connection: &mut SqliteConnection) -> Result<Vec<Fp>> {
-    let mut nfs = sqlx::query("SELECT hash FROM nfs")
+use crate::{
+    as_byte256,
+    db::{
+        list_notes, load_ballot_tree_height, load_cmx_frontier, store_cmx_frontier,
+        store_cmx_root,
+    },
+    election::Election,
+    errors::VoteError,
+    source::LwdBlockSource,
+    VoteNote, DEPTH,
+};
+
+pub async fn list_nf_ranges(connection: &mut SqliteConnection, id_election: u32) -> Result<Vec<Fp>> {
+    let mut nfs = sqlx::query("SELECT hash FROM nfs WHERE election = ?")
+    .bind(id_election)
     .map(|row: SqliteRow| {
         let v: Vec<u8> = row.get(0);
         let v = Fp::from_repr(v.try_into().unwrap()).unwrap();
@@ -16,15 +39,16 @@ pub async fn list_nf_ranges(connection: &mut SqliteConnection) -> Result<Vec<Fp>
     Ok(nf_tree)
 }
 
-pub async fn compute_nf_root(connection: &mut SqliteConnection) -> Result<OrchardHash> {
-    let nf_tree = list_nf_ranges(connection).await?;
+pub async fn compute_nf_root(connection: &mut SqliteConnection, id_election: u32) -> Result<OrchardHash> {
+    let nf_tree = list_nf_ranges(connection, id_election).await?;
     let (nf_root, _) = calculate_merkle_paths(0, &[], &nf_tree);
 
     Ok(OrchardHash(nf_root.to_repr()))
 }
 
-pub async fn list_cmxs(connection: &mut SqliteConnection) -> Result<Vec<Fp>> {
-    let cmx_tree = sqlx::query("SELECT hash FROM cmxs ORDER BY id_cmx")
+pub async fn list_cmxs(connection: &mut SqliteConnection, id_election: u32) -> Result<Vec<Fp>> {
+    let cmx_tree = sqlx::query("SELECT hash FROM cmxs WHERE election = ? ORDER BY id_cmx")
+    .bind(id_election)
     .map(|row: SqliteRow| {
         let v: Vec<u8> = row.get(0);
         let v = Fp::from_repr(v.try_into().unwrap()).unwrap();
@@ -35,8 +59,11 @@ pub async fn list_cmxs(connection: &mut SqliteConnection) -> Result<Vec<Fp>> {
     Ok(cmx_tree)
 }
 
-pub async fn compute_cmx_root(connection: &mut SqliteConnection) -> Result<(OrchardHash, Option<Frontier>)> {
-    let cmx_tree = list_cmxs(connection).await?;
+pub async fn compute_cmx_root(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> Result<(OrchardHash, Option<Frontier>)> {
+    let cmx_tree = list_cmxs(connection, id_election).await?;
     let (cmx_root, frontier) = if cmx_tree.is_empty() {
         let (cmx_root, _) = calculate_merkle_paths(0, &[], &[]);
         (cmx_root, None)
@@ -61,6 +88,253 @@ pub async fn compute_cmx_root(connection: &mut SqliteConnection) -> Result<(Orch
     Ok((OrchardHash(cmx_root.to_repr()), frontier))
 }
 
+/// Unspent notes of the account, with the nullifier range and the
+/// Merkle paths needed to vote with them. The paths of all the notes
+/// are computed in a single pass over each tree, which also gives
+/// the nf and cmx roots of the synced data, returned with the notes
+pub async fn list_vote_notes(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    account: u32,
+    fvk: &FullViewingKey,
+) -> crate::Result<(Vec<VoteNote>, OrchardHash, OrchardHash)> {
+    let notes = list_notes(connection, id_election, account, fvk).await?;
+    let nf_tree = list_nf_ranges(connection, id_election).await?;
+    let cmx_tree = list_cmxs(connection, id_election).await?;
+
+    let mut nf_positions = vec![];
+    let mut cmx_positions = vec![];
+    for (note, position) in notes.iter() {
+        let nf = Fp::from_repr(note.nullifier(fvk).to_bytes()).unwrap();
+        // nf_tree is a sorted list of (start, end) pairs
+        let nf_position = match nf_tree.binary_search(&nf) {
+            Ok(p) => p & !1,
+            Err(p) if p % 2 == 1 => p - 1,
+            Err(_) => return Err(VoteError::OutOfRange(*position as usize)),
+        };
+        if *position as usize >= cmx_tree.len() {
+            return Err(VoteError::OutOfRange(*position as usize));
+        }
+        nf_positions.push(nf_position as u32);
+        cmx_positions.push(*position);
+    }
+    let (nf_root, nf_paths) = calculate_merkle_paths(0, &nf_positions, &nf_tree);
+    let (cmx_root, cmx_paths) = calculate_merkle_paths(0, &cmx_positions, &cmx_tree);
+
+    let vote_notes = notes
+        .into_iter()
+        .zip(nf_paths.iter().zip(cmx_paths.iter()))
+        .map(|((note, position), (nf_path, cmx_path))| {
+            let nf_start = nf_tree[nf_path.position as usize];
+            VoteNote {
+                nf: note.nullifier(fvk),
+                note,
+                idx: position as usize,
+                nf_start: Nullifier::from_bytes(&nf_start.to_repr()).unwrap(),
+                nf_path: to_merkle_path(nf_path.position, &nf_path.path),
+                cmx_path: to_merkle_path(cmx_path.position, &cmx_path.path),
+            }
+        })
+        .collect();
+    Ok((
+        vote_notes,
+        OrchardHash(nf_root.to_repr()),
+        OrchardHash(cmx_root.to_repr()),
+    ))
+}
+
+fn to_merkle_path(position: u32, path: &[Fp]) -> MerklePath {
+    let path = path
+        .iter()
+        .map(|h| MerkleHashOrchard::from_bytes(&h.to_repr()).unwrap())
+        .collect::<Vec<_>>();
+    MerklePath::from_parts(position, path.try_into().unwrap())
+}
+
+/// Check the commitments stored for the election against the Orchard
+/// tree states of the server: appending them to the tree at the start
+/// height must give the tree at the end height. This catches servers
+/// that omit or inject actions
+pub async fn check_cmx_tree(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    election: &Election,
+    source: &mut LwdBlockSource,
+) -> crate::Result<()> {
+    let start_tree = source.get_tree_state(election.start_height).await?;
+    let end_tree = source.get_tree_state(election.end_height).await?;
+    let mut tree = parse_orchard_tree(&start_tree.orchard_tree)?;
+    let end_tree = parse_orchard_tree(&end_tree.orchard_tree)?;
+
+    let cmxs = list_cmxs(connection, id_election).await?;
+    let expected = end_tree.size().checked_sub(tree.size());
+    if expected != Some(cmxs.len()) {
+        return Err(VoteError::CmxTreeMismatch(format!(
+            "{} actions stored, tree grew from {} to {}",
+            cmxs.len(),
+            tree.size(),
+            end_tree.size()
+        )));
+    }
+    for cmx in cmxs {
+        let node = MerkleHashOrchard::from_bytes(&cmx.to_repr()).unwrap();
+        tree.append(node)
+            .map_err(|_| anyhow!("Commitment tree is full"))?;
+    }
+    if tree.root() != end_tree.root() {
+        return Err(VoteError::CmxTreeMismatch(format!(
+            "root {} instead of {}",
+            hex::encode(tree.root().to_bytes()),
+            hex::encode(end_tree.root().to_bytes())
+        )));
+    }
+    Ok(())
+}
+
+fn parse_orchard_tree(tree: &str) -> Result<CommitmentTree<MerkleHashOrchard, { DEPTH as u8 }>> {
+    // before the first Orchard action
+    if tree.is_empty() {
+        return Ok(CommitmentTree::empty());
+    }
+    let tree = hex::decode(tree)?;
+    let tree = read_commitment_tree(&*tree)?;
+    Ok(tree)
+}
+
+/// Append the output commitments of an accepted ballot to the ballot
+/// tree of the election, then save the new root and frontier at `height`.
+/// Returns the new root
+pub async fn append_ballot_cmxs(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    cmxs: &[Vec<u8>],
+) -> Result<OrchardHash> {
+    let tree = load_ballot_tree(connection, id_election).await?;
+    let tree = append_cmxs(tree, cmxs)?;
+    save_ballot_tree(connection, id_election, height, tree.as_ref()).await
+}
+
+/// Root of the ballot tree made of `cmxs`, without the database
+pub fn compute_ballot_root(cmxs: &[Vec<u8>]) -> Result<OrchardHash> {
+    let tree = append_cmxs(None, cmxs)?;
+    Ok(OrchardHash(ballot_tree_root(tree.as_ref())))
+}
+
+fn append_cmxs(
+    mut tree: Option<NonEmptyFrontier<MerkleHashOrchard>>,
+    cmxs: &[Vec<u8>],
+) -> Result<Option<NonEmptyFrontier<MerkleHashOrchard>>> {
+    for cmx in cmxs {
+        let leaf = MerkleHashOrchard::from_bytes(&as_byte256(cmx))
+            .into_option()
+            .ok_or(anyhow!("Invalid cmx {}", hex::encode(cmx)))?;
+        tree = Some(match tree {
+            Some(mut tree) => {
+                tree.append(leaf);
+                tree
+            }
+            None => NonEmptyFrontier::new(leaf),
+        });
+    }
+    Ok(tree)
+}
+
+fn ballot_tree_root(tree: Option<&NonEmptyFrontier<MerkleHashOrchard>>) -> [u8; 32] {
+    let root_level = Level::from(DEPTH as u8);
+    let root = match tree {
+        Some(tree) => tree.root(Some(root_level)),
+        None => MerkleHashOrchard::empty_root(root_level),
+    };
+    root.to_bytes()
+}
+
+/// Save the root and frontier of the ballot tree at `height`, even
+/// if no ballot was accepted since the previous checkpoint
+pub async fn checkpoint_ballot_tree(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+) -> Result<OrchardHash> {
+    let tree = load_ballot_tree(connection, id_election).await?;
+    save_ballot_tree(connection, id_election, height, tree.as_ref()).await
+}
+
+async fn load_ballot_tree(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+) -> Result<Option<NonEmptyFrontier<MerkleHashOrchard>>> {
+    let Some((_, frontier)) = load_cmx_frontier(connection, id_election).await? else {
+        return Ok(None);
+    };
+    Ok(Some(from_vote_frontier(&frontier)?))
+}
+
+async fn save_ballot_tree(
+    connection: &mut SqliteConnection,
+    id_election: u32,
+    height: u32,
+    tree: Option<&NonEmptyFrontier<MerkleHashOrchard>>,
+) -> Result<OrchardHash> {
+    // the latest tree is the one at the highest height, so the
+    // heights must not go back or ballots would be dropped from the tree
+    if let Some(last_height) = load_ballot_tree_height(connection, id_election).await? {
+        if height < last_height {
+            anyhow::bail!("Ballot tree is at height {last_height}, cannot save it at {height}");
+        }
+    }
+    let root = ballot_tree_root(tree);
+    store_cmx_root(connection, id_election, height, &root).await?;
+    // an empty tree has no frontier
+    if let Some(tree) = tree {
+        store_cmx_frontier(connection, id_election, height, &to_vote_frontier(tree)).await?;
+    }
+    Ok(OrchardHash(root))
+}
+
+// The vote frontier has the full authentication path of the last leaf,
+// the incremental frontier only has the ommers on its left
+fn from_vote_frontier(frontier: &Frontier) -> Result<NonEmptyFrontier<MerkleHashOrchard>> {
+    let to_node = |h: &OrchardHash| {
+        MerkleHashOrchard::from_bytes(&h.0)
+            .into_option()
+            .ok_or(anyhow!("Invalid frontier node"))
+    };
+    let position = frontier.position as u64;
+    let ommers = frontier
+        .ommers
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| (position >> i) & 1 == 1)
+        .map(|(_, o)| to_node(o))
+        .collect::<Result<Vec<_>>>()?;
+    let leaf = to_node(&frontier.leaf)?;
+    let tree = NonEmptyFrontier::from_parts(Position::from(position), leaf, ommers)
+        .map_err(|e| anyhow!("Invalid frontier: {e:?}"))?;
+    Ok(tree)
+}
+
+fn to_vote_frontier(tree: &NonEmptyFrontier<MerkleHashOrchard>) -> Frontier {
+    let position = u64::from(tree.position());
+    let mut ommers = tree.ommers().iter();
+    let path = (0..DEPTH as u8)
+        .map(|i| {
+            let level = Level::from(i);
+            let node = if (position >> i) & 1 == 1 {
+                ommers.next().cloned().unwrap()
+            } else {
+                MerkleHashOrchard::empty_root(level)
+            };
+            OrchardHash(node.to_bytes())
+        })
+        .collect::<Vec<_>>();
+    Frontier {
+        position: position.try_into().unwrap(),
+        leaf: OrchardHash(tree.leaf().to_bytes()),
+        ommers: path,
+    }
+}
+
 pub fn build_nf_ranges(nfs: impl IntoIterator<Item = Fp>) -> Vec<Fp> {
     let mut prev = Fp::zero();
     let mut leaves = vec![];